@@ -0,0 +1,279 @@
+/*
+Binary cache shared between pacage servers building with the same inputs, a directory or an
+HTTP endpoint: `binary_cache = "/mnt/pacage-cache"` or `binary_cache = "http://cache.lan/pacage"`.
+
+==== <binary_cache>/<fingerprint>/ ====
+├ SHA256SUMS                            # "<sha256>  <pkgfile>", written last
+├ vi-1:070224-6-x86_64.pkg.tar.zst      # Every package of a split package
+├ vi-1:070224-6-x86_64.pkg.tar.zst.sig  # With gpg_key
+└ [..]
+========
+
+A build is looked up by its inputs fingerprint (see fingerprint.rs) before starting it, and
+uploaded after success. HTTP caches are read with GET and written with PUT (curl).
+*/
+
+use log::debug;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use thiserror::Error;
+
+use crate::cmd::CmdError;
+use crate::db::hash_file;
+
+const INDEX: &str = "SHA256SUMS";
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] CmdError),
+
+    #[error("Invalid index line: {0}")]
+    Index(String),
+
+    #[error("Checksum mismatch for {0}")]
+    Checksum(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryCache {
+    Dir(PathBuf),
+    Http(String),
+}
+
+// (success, stdout, stderr lines) of curl
+fn curl(args: &[&str]) -> Result<(bool, String, Vec<String>), io::Error> {
+    let out = Command::new("curl").arg("-sS").args(args).output()?;
+    Ok((
+        out.status.success(),
+        String::from_utf8_lossy(&out.stdout).to_string(),
+        String::from_utf8_lossy(&out.stderr)
+            .lines()
+            .map(|l| l.to_string())
+            .collect(),
+    ))
+}
+
+impl BinaryCache {
+    pub fn new(value: &str) -> Self {
+        if value.starts_with("http://") || value.starts_with("https://") {
+            Self::Http(value.trim_end_matches('/').to_string())
+        } else {
+            Self::Dir(PathBuf::from(value))
+        }
+    }
+
+    // Download an entry file to dest, false if it is not in the cache
+    fn get(&self, path: &str, dest: &Path) -> Result<bool, CacheError> {
+        match self {
+            Self::Dir(dir) => match fs::copy(dir.join(path), dest) {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e)?,
+            },
+            Self::Http(url) => {
+                let url = format!("{}/{}", url, path);
+                let dest = dest.to_string_lossy();
+                let (success, code, err) = curl(&["-w", "%{http_code}", "-o", &dest, &url])?;
+                match code.as_str() {
+                    "200" if success => Ok(true),
+                    "404" => {
+                        fs::remove_file(dest.as_ref()).ok();
+                        Ok(false)
+                    }
+                    _ => Err(CmdError::from_output(err))?,
+                }
+            }
+        }
+    }
+
+    fn put(&self, src: &Path, path: &str) -> Result<(), CacheError> {
+        match self {
+            Self::Dir(dir) => {
+                let dest = dir.join(path);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // Readers never see a partial file
+                let tmp = dest.with_extension("part");
+                fs::copy(src, &tmp)?;
+                fs::rename(tmp, dest)?;
+            }
+            Self::Http(url) => {
+                let url = format!("{}/{}", url, path);
+                let (success, _, err) =
+                    curl(&["-f", "-H", "Expect:", "-T", &src.to_string_lossy(), &url])?;
+                if !success {
+                    Err(CmdError::from_output(err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the cached packages of the fingerprint into dir, None on cache miss
+    pub fn fetch(&self, fingerprint: &str, dir: &Path) -> Result<Option<Vec<String>>, CacheError> {
+        let index_path = dir.join(format!(".{}.{}", fingerprint, INDEX));
+        if !self.get(&format!("{}/{}", fingerprint, INDEX), &index_path)? {
+            debug!("Binary cache miss for {}", fingerprint);
+            return Ok(None);
+        }
+        let index = fs::read_to_string(&index_path);
+        fs::remove_file(&index_path).ok();
+        let mut files = Vec::new();
+        for line in index?.lines().filter(|line| !line.is_empty()) {
+            let Some((sum, file)) = line.split_once("  ") else {
+                Err(CacheError::Index(line.to_string()))?
+            };
+            if file.contains('/') {
+                Err(CacheError::Index(line.to_string()))?
+            }
+            let tmp = dir.join(format!(".{}.part", file));
+            if !self.get(&format!("{}/{}", fingerprint, file), &tmp)? {
+                return Ok(None);
+            }
+            if hash_file(&tmp)?.1 != sum {
+                fs::remove_file(&tmp).ok();
+                Err(CacheError::Checksum(file.to_string()))?
+            }
+            fs::rename(tmp, dir.join(file))?;
+            files.push(file.to_string());
+        }
+        debug!("Fetched {} from the binary cache", files.join(", "));
+        Ok(Some(files))
+    }
+
+    /// Upload the package files (in dir) built with the fingerprint
+    pub fn upload(
+        &self,
+        fingerprint: &str,
+        dir: &Path,
+        files: &[String],
+    ) -> Result<(), CacheError> {
+        let mut index = String::new();
+        for file in files {
+            let path = dir.join(file);
+            index.push_str(&format!("{}  {}\n", hash_file(&path)?.1, file));
+            self.put(&path, &format!("{}/{}", fingerprint, file))?;
+        }
+        let index_path = dir.join(format!(".{}.{}", fingerprint, INDEX));
+        fs::write(&index_path, index)?;
+        let res = self.put(&index_path, &format!("{}/{}", fingerprint, INDEX));
+        fs::remove_file(index_path).ok();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::tests::mktemp;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // GET/PUT only, one request per connection
+    fn http_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap().to_string());
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((key, value)) = header.split_once(':') {
+                        if key.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let (code, body) = match method {
+                    "PUT" => {
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        files.insert(path, body);
+                        ("201 Created", Vec::new())
+                    }
+                    _ => match files.get(&path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    },
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    code,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        format!("http://{}/cache", addr)
+    }
+
+    fn round_trip(cache: BinaryCache) {
+        let build = mktemp();
+        let repo = mktemp();
+        let pkgfile = "foo-1.0-1-x86_64.pkg.tar.zst".to_string();
+        fs::write(build.join(&pkgfile), "package").unwrap();
+
+        assert_eq!(cache.fetch("abcd", &repo).unwrap(), None);
+        cache
+            .upload("abcd", &build, std::slice::from_ref(&pkgfile))
+            .unwrap();
+        assert_eq!(
+            cache.fetch("abcd", &repo).unwrap(),
+            Some(vec![pkgfile.clone()])
+        );
+        assert_eq!(fs::read_to_string(repo.join(&pkgfile)).unwrap(), "package");
+        // Only the package is left
+        assert_eq!(fs::read_dir(&repo).unwrap().count(), 1);
+
+        // Corrupted entry
+        fs::write(build.join(&pkgfile), "corrupted").unwrap();
+        cache
+            .put(&build.join(&pkgfile), &format!("abcd/{}", pkgfile))
+            .unwrap();
+        assert!(matches!(
+            cache.fetch("abcd", &repo),
+            Err(CacheError::Checksum(_))
+        ));
+        fs::remove_dir_all(build).ok();
+        fs::remove_dir_all(repo).ok();
+    }
+
+    #[test]
+    fn dir() {
+        let dir = mktemp();
+        round_trip(BinaryCache::new(&dir.to_string_lossy()));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn http() {
+        let url = http_server();
+        assert_eq!(
+            BinaryCache::new(&format!("{}/", url)),
+            BinaryCache::Http(url.clone())
+        );
+        round_trip(BinaryCache::new(&url));
+    }
+}
@@ -1,8 +1,9 @@
-use crate::conf::{Makepkg, Package};
+use crate::conf::{Makepkg, Package, Pgo};
 use crossbeam_channel::{bounded, Receiver, Sender};
 use log::{error, info};
 use std::cmp::max;
 use std::collections::HashSet;
+use std::env;
 use std::fmt::Display;
 use std::fs::{self};
 use std::path::{Path, PathBuf};
@@ -11,10 +12,13 @@ use std::{io, thread};
 use thiserror::Error;
 
 use crate::cmd::{command, out_to_file, write_last_lines, CmdError, ExecError, NOENV};
-use crate::conf::{Conf, BUILD_SCRIPT_FILE};
+use crate::conf::{Conf, Target, BUILD_SCRIPT_FILE};
 use crate::format::{self, SrcInfo};
+use crate::utils::file_lock::FileLock;
 
 const CONTAINER_NAME: &str = "pacage_builder";
+// Image of the builder and the throw-away containers
+pub const BUILDER_IMAGE: &str = "archlinux:base-devel";
 
 pub struct DurationPrinter(Duration);
 
@@ -59,6 +63,16 @@ pub enum BuilderError {
 
 pub struct Builder {
     container_runner: String,
+    // The container name is shared by every server dir of the runner user
+    _lock: FileLock,
+}
+
+// Lock of a container name, per user like the rootless containers
+fn container_lock_path(container: &str) -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("{}.lock", container))
 }
 
 pub fn should_build(pkgbuilds: &HashSet<SrcInfo>) -> bool {
@@ -83,6 +97,7 @@ impl Builder {
         let container_runner = conf.container_runner.clone();
         let host_server_dir = conf.host_server_dir.clone();
         let build_log_dir = conf.build_log_dir.clone();
+        let wait_lock = conf.wait_lock;
         thread::spawn(move || {
             sender
                 .send(Self::new(
@@ -90,6 +105,7 @@ impl Builder {
                     container_runner,
                     &host_server_dir,
                     &build_log_dir,
+                    wait_lock,
                 ))
                 .ok();
         });
@@ -101,7 +117,9 @@ impl Builder {
         container_runner: String,
         host_server_dir: &Option<PathBuf>,
         build_log_dir: &Option<PathBuf>,
+        wait_lock: bool,
     ) -> Result<Self, BuilderError> {
+        let lock = FileLock::new(&container_lock_path(CONTAINER_NAME), wait_lock)?;
         info!("Initiating builder container...");
         Self::stop_builder(&container_runner);
 
@@ -127,7 +145,7 @@ impl Builder {
                 CONTAINER_NAME,
                 "-d", // detach
                 &format!("-v={}:/build", server_dir),
-                "archlinux:base-devel",
+                BUILDER_IMAGE,
                 "sh",
                 "-c",
                 "sleep infinity",
@@ -170,7 +188,10 @@ impl Builder {
             Err(CmdError::from_output(out))?;
         }
         info!("Builder container initiated");
-        Ok(Self { container_runner })
+        Ok(Self {
+            container_runner,
+            _lock: lock,
+        })
     }
 
     pub fn download_srcs(
@@ -187,7 +208,7 @@ impl Builder {
                 let ret = ret.clone();
                 s.spawn(move || {
                     while let Ok((srcinfo, pkg)) = pkgs.recv() {
-                        match self.download_src(conf, srcinfo, pkg.makepkg.as_ref()) {
+                        match self.download_src(conf, srcinfo, &pkg) {
                             Ok(srcinfo) => {
                                 ret.send((srcinfo, pkg)).ok();
                             }
@@ -205,16 +226,18 @@ impl Builder {
         &self,
         conf: &Conf,
         srcinfo: SrcInfo,
-        makepkgconf: Option<&Makepkg>,
+        pkg: &Package,
     ) -> Result<SrcInfo, BuilderError> {
         let name = srcinfo.name.as_str();
         let makepkgconf_path = Path::new(&conf.server_dir)
             .join("srcs")
             .join(format!("makepkg_{}.conf", name));
+        // Sources do not depend on the target
         fs::write(
             &makepkgconf_path,
-            Makepkg::get_conf_file(&conf, makepkgconf, name)?,
+            Makepkg::get_conf_file(conf, pkg, &conf.targets[0])?,
         )?;
+        let _src_lock = conf.lock_src(name)?;
         let src_path = conf.pkg_src(name);
         if src_path.exists() {
             fs::remove_dir_all(conf.pkg_src(name))?;
@@ -266,7 +289,7 @@ impl Builder {
                 .map(|m| m.modified()) // Result::flatten
                 .is_ok_and(|t| t.is_ok_and(|new| new > makepkg_lastedit))
             {
-                Ok(SrcInfo::new(&pkgsdir, &srcinfo.name, true)?)
+                self.srcinfo(conf, &srcinfo.name)
             } else {
                 Ok(srcinfo)
             }
@@ -275,38 +298,197 @@ impl Builder {
         }
     }
 
+    // Generate the .SRCINFO inside the builder, the PKGBUILD is never sourced on the host
+    pub fn srcinfo(&self, conf: &Conf, name: &str) -> Result<SrcInfo, BuilderError> {
+        info!("[{}] Generating .SRCINFO...", name);
+        let (status, out, _) = command(
+            &[
+                &conf.container_runner,
+                "exec",
+                "--workdir=/build",
+                "--env=HOME=/tmp",
+                CONTAINER_NAME,
+                "bash",
+                &format!("/build/{}", BUILD_SCRIPT_FILE),
+                "srcinfo",
+                name,
+            ],
+            &conf.server_dir,
+            NOENV,
+        )?;
+        match out_to_file(&conf.build_log_dir, name, "srcinfo", &out, status.success()) {
+            Ok(Some(file)) => info!("[{}] Srcinfo logs writed to {}", name, file),
+            Ok(None) => {}
+            Err(e) => error!("[{}] Failed to write output to logs: {}", name, e),
+        }
+        if !status.success() {
+            error!("[{}] Failed to generate .SRCINFO", name);
+            write_last_lines(&out, 10);
+            Err(CmdError::from_output(out))?
+        }
+        Ok(SrcInfo::new(&conf.pkgs_dir(), name)?)
+    }
+
     pub fn build_pkg(
         &self,
         conf: &Conf,
         pkg: &Package,
+        target: &Target,
         // makepkgconf: Option<&Makepkg>,
     ) -> Result<(), BuilderError> {
+        let _src_lock = conf.lock_src(&pkg.name)?;
+        let Some(pgo) = &pkg.pgo else {
+            return self.makepkg(conf, pkg, target, None);
+        };
         let name = &pkg.name;
-        info!("[{}] Building/packaging the sources...", name);
-        let makepkgconf = pkg.makepkg.as_ref();
-        let makepkgconf_path = Path::new(&conf.server_dir)
-            .join("srcs")
-            .join(format!("makepkg_{}.conf", name));
-        fs::write(
-            &makepkgconf_path,
-            Makepkg::get_conf_file(conf, makepkgconf, name)?,
-        )?;
+        let pgo_dir = conf.server_dir.join(conf.pgo_subdir(name, target));
+        if has_profiles(&pgo_dir) {
+            info!("[{}] Reusing PGO profiles from {}", name, pgo_dir.display());
+        } else {
+            fs::create_dir_all(&pgo_dir)?;
+            let instrumented = conf.server_dir.join(pgo_pkgs_subdir(name));
+            if instrumented.exists() {
+                fs::remove_dir_all(&instrumented)?;
+            }
+            info!("[{}] PGO: instrumented build...", name);
+            self.makepkg(conf, pkg, target, Some(PgoStage::Generate))?;
+            let res = self.pgo_train(conf, pkg, target, pgo);
+            fs::remove_dir_all(&instrumented).ok();
+            res?;
+            if !has_profiles(&pgo_dir) {
+                error!("[{}] PGO: the training did not produce any profile", name);
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No profile in {}", pgo_dir.display()),
+                ))?
+            }
+        }
+        self.makepkg(conf, pkg, target, Some(PgoStage::Use))
+    }
+
+    // Install the instrumented package in a throw-away container and run the training command
+    fn pgo_train(
+        &self,
+        conf: &Conf,
+        pkg: &Package,
+        target: &Target,
+        pgo: &Pgo,
+    ) -> Result<(), BuilderError> {
+        let name = &pkg.name;
+        // The src lock is per server dir, the container name is not
+        let container = format!("pacage_pgo_{}", name);
+        let _lock = FileLock::new(&container_lock_path(&container), conf.wait_lock)?;
+        info!("[{}] PGO: training...", name);
+        let server_dir = conf.host_server_dir.as_ref().unwrap_or(&conf.server_dir);
         let (status, out, elapsed) = command(
             &[
                 &conf.container_runner,
-                "exec",
+                "run",
+                "--rm",
+                &format!("--name={}", container),
+                &format!("-v={}:/build", server_dir.display()),
                 "--workdir=/build",
-                "--env=HOME=/tmp",
-                "--env=CCACHE_DIR=/build/cache/ccache/",
-                CONTAINER_NAME,
+                &format!("--env=PACAGE_PGO_TRAIN={}", pgo.train),
+                &format!(
+                    "--env=PACAGE_PGO_DIR=/build/{}",
+                    conf.pgo_subdir(name, target).display()
+                ),
+                &format!(
+                    "--env=PACAGE_PGO_PKGS=/build/{}",
+                    pgo_pkgs_subdir(name).display()
+                ),
+                BUILDER_IMAGE,
                 "bash",
                 &format!("/build/{}", BUILD_SCRIPT_FILE),
-                "build",
+                "train",
                 name,
             ],
             &conf.server_dir,
             NOENV,
         )?;
+        match out_to_file(&conf.build_log_dir, name, "train", &out, status.success()) {
+            Ok(Some(file)) => info!("[{}] Training logs writed to {}", name, file),
+            Ok(None) => {}
+            Err(e) => error!("[{}] Failed to write output to logs: {}", name, e),
+        }
+        if !status.success() {
+            error!(
+                "[{}] PGO: training failed in {} ->",
+                name,
+                DurationPrinter(elapsed)
+            );
+            write_last_lines(&out, 10);
+            Err(CmdError::from_output(out))?
+        }
+        info!("[{}] PGO: trained in {}", name, DurationPrinter(elapsed));
+        Ok(())
+    }
+
+    fn makepkg(
+        &self,
+        conf: &Conf,
+        pkg: &Package,
+        target: &Target,
+        pgo: Option<PgoStage>,
+    ) -> Result<(), BuilderError> {
+        let name = &pkg.name;
+        info!(
+            "[{}] Building/packaging the sources for {}...",
+            name, target.name
+        );
+        let mut makepkg = Makepkg::resolve(conf, pkg, target)?;
+        let repo_subdir = match pgo {
+            Some(stage) => {
+                let pgo_dir = conf.pgo_subdir(name, target);
+                let clang = makepkg.env.get("CC").is_some_and(|cc| cc.contains("clang"));
+                let rust_profile = conf.server_dir.join(&pgo_dir).join("default.profdata");
+                for line in stage.makepkg_lines(
+                    &Path::new("/build").join(pgo_dir),
+                    clang,
+                    rust_profile.exists(),
+                ) {
+                    makepkg.file.push(&line);
+                }
+                match stage {
+                    PgoStage::Generate => pgo_pkgs_subdir(name),
+                    PgoStage::Use => conf.repo_subdir(target),
+                }
+            }
+            None => conf.repo_subdir(target),
+        };
+        let makepkgconf_path = Path::new(&conf.server_dir)
+            .join("srcs")
+            .join(format!("makepkg_{}.conf", name));
+        fs::write(&makepkgconf_path, makepkg.file.to_string())?;
+        let makepkg_flags = format!("--env=PACAGE_MAKEPKG_FLAGS={}", makepkg.flags.join(" "));
+        let toolchain_pkgs = format!("--env=PACAGE_TOOLCHAIN_PKGS={}", makepkg.packages.join(" "));
+        let repo_dir = format!("--env=PACAGE_REPO_DIR=/build/{}", repo_subdir.display());
+        // Every target/PGO stage build from a copy of the prepared sources
+        let build_dir = if conf.targets.len() > 1 || pgo.is_some() {
+            format!("--env=PACAGE_BUILDDIR=/build/srcs/.{}", target.name)
+        } else {
+            "--env=PACAGE_BUILDDIR=".to_string()
+        };
+        let script = format!("/build/{}", BUILD_SCRIPT_FILE);
+        let env: Vec<String> = makepkg
+            .env
+            .iter()
+            .map(|(var, value)| format!("--env={}={}", var, value))
+            .collect();
+        let mut args = vec![
+            conf.container_runner.as_str(),
+            "exec",
+            "--workdir=/build",
+            "--env=HOME=/tmp",
+            "--env=CCACHE_DIR=/build/cache/ccache/",
+            &makepkg_flags,
+            &toolchain_pkgs,
+            &repo_dir,
+            &build_dir,
+        ];
+        args.extend(env.iter().map(|a| a.as_str()));
+        args.extend([CONTAINER_NAME, "bash", &script, "build", name]);
+        let (status, out, elapsed) = command(&args, &conf.server_dir, NOENV)?;
         fs::remove_file(makepkgconf_path).ok();
         match out_to_file(&conf.build_log_dir, name, "build", &out, status.success()) {
             Ok(Some(file)) => info!("[{}] Build logs writed to {}", name, file),
@@ -332,6 +514,73 @@ impl Builder {
     }
 }
 
+// Two stages of a PGO build
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PgoStage {
+    // Instrumented build
+    Generate,
+    // Optimised build from the collected profiles
+    Use,
+}
+
+impl PgoStage {
+    // Lines appended to the makepkg.conf, dir is the profile dir inside the builder
+    fn makepkg_lines(&self, dir: &Path, clang: bool, rust_profile: bool) -> Vec<String> {
+        let dir = dir.display();
+        let (cflags, ldflags, rustflags) = match self {
+            PgoStage::Generate => (
+                format!("-fprofile-generate={} -fprofile-update=atomic", dir),
+                Some(format!("-fprofile-generate={}", dir)),
+                Some(format!("-Cprofile-generate={}", dir)),
+            ),
+            PgoStage::Use if clang => (format!("-fprofile-use={}", dir), None, None),
+            // Profiles can be older than the sources when reused
+            PgoStage::Use => (
+                format!(
+                    "-fprofile-use={} -fprofile-partial-training -Wno-missing-profile -Wno-coverage-mismatch",
+                    dir
+                ),
+                None,
+                None,
+            ),
+        };
+        let rustflags = match self {
+            PgoStage::Use if rust_profile => {
+                Some(format!("-Cprofile-use={}/default.profdata", dir))
+            }
+            _ => rustflags,
+        };
+        let mut lines = vec![
+            format!("CFLAGS+=\" {}\"", cflags),
+            format!("CXXFLAGS+=\" {}\"", cflags),
+        ];
+        if let Some(ldflags) = ldflags {
+            lines.push(format!("LDFLAGS+=\" {}\"", ldflags));
+        }
+        if let Some(rustflags) = rustflags {
+            lines.push(format!("RUSTFLAGS+=\" {}\"", rustflags));
+        }
+        lines
+    }
+}
+
+// Instrumented packages of a PGO build, relative to the server dir
+fn pgo_pkgs_subdir(pkg: &str) -> PathBuf {
+    Path::new("srcs").join(".pgo").join(pkg)
+}
+
+// gcc writes .gcda files, clang/rustc profiles are merged into default.profdata
+fn has_profiles(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        path.extension().is_some_and(|ext| ext == "gcda")
+            || path.file_name().is_some_and(|n| n == "default.profdata")
+    })
+}
+
 impl Drop for Builder {
     fn drop(&mut self) {
         info!("Stoping builder...");
@@ -339,3 +588,32 @@ impl Drop for Builder {
         info!("Builder stoped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgo_makepkg_lines() {
+        let dir = Path::new("/build/cache/pgo/foo/pacage");
+        assert_eq!(
+            PgoStage::Generate.makepkg_lines(dir, false, false),
+            [
+                "CFLAGS+=\" -fprofile-generate=/build/cache/pgo/foo/pacage -fprofile-update=atomic\"",
+                "CXXFLAGS+=\" -fprofile-generate=/build/cache/pgo/foo/pacage -fprofile-update=atomic\"",
+                "LDFLAGS+=\" -fprofile-generate=/build/cache/pgo/foo/pacage\"",
+                "RUSTFLAGS+=\" -Cprofile-generate=/build/cache/pgo/foo/pacage\"",
+            ]
+        );
+        assert_eq!(
+            PgoStage::Use.makepkg_lines(dir, true, true),
+            [
+                "CFLAGS+=\" -fprofile-use=/build/cache/pgo/foo/pacage\"",
+                "CXXFLAGS+=\" -fprofile-use=/build/cache/pgo/foo/pacage\"",
+                "RUSTFLAGS+=\" -Cprofile-use=/build/cache/pgo/foo/pacage/default.profdata\"",
+            ]
+        );
+        assert!(PgoStage::Use.makepkg_lines(dir, false, false)[0]
+            .contains("-fprofile-partial-training"));
+    }
+}
@@ -150,6 +150,16 @@ where
     _command(cmd)
 }
 
+// Terminal attached to the command, for interactive programs
+pub fn interactive<P: AsRef<Path>>(args: &[&str], current_dir: P) -> Result<ExitStatus, ExecError> {
+    assert!(!args.is_empty());
+    debug!("Executing interactive {:?}", args);
+    Ok(Command::new(args[0])
+        .args(&args[1..])
+        .current_dir(current_dir)
+        .status()?)
+}
+
 pub fn out_to_file(
     build_log_dir: &Option<PathBuf>,
     pkg: &str,
@@ -0,0 +1,255 @@
+/*
+Package archive compressions supported by pacman, detected from the file name (PKGEXT):
+vi-1:070224-6-x86_64.pkg.tar.zst
+vi-1:070224-6-x86_64.pkg.tar.xz
+vi-1:070224-6-x86_64.pkg.tar        # No compression
+lzo has no rust decoder, lzop is run for it.
+The repo dbs (<repo>.db -> <repo>.db.tar.gz) are detected from their magic bytes, they are
+written with gzip, zstd or xz (db_compression).
+*/
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use lz4_flex::frame::FrameDecoder;
+use lzma_rust2::XzReader;
+use lzma_rust2::{XzOptions, XzWriter};
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::CompressionLevel;
+use std::fs::File;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zst,
+    Xz,
+    Gz,
+    Bz2,
+    Lz4,
+    Lzo,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 7] = [
+        Self::Zst,
+        Self::Xz,
+        Self::Gz,
+        Self::Bz2,
+        Self::Lz4,
+        Self::Lzo,
+        Self::None,
+    ];
+
+    /// PKGEXT of the compression
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => ".pkg.tar",
+            Self::Zst => ".pkg.tar.zst",
+            Self::Xz => ".pkg.tar.xz",
+            Self::Gz => ".pkg.tar.gz",
+            Self::Bz2 => ".pkg.tar.bz2",
+            Self::Lz4 => ".pkg.tar.lz4",
+            Self::Lzo => ".pkg.tar.lzo",
+        }
+    }
+
+    /// Suffix after .tar
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Zst => ".zst",
+            Self::Xz => ".xz",
+            Self::Gz => ".gz",
+            Self::Bz2 => ".bz2",
+            Self::Lz4 => ".lz4",
+            Self::Lzo => ".lzo",
+        }
+    }
+
+    /// db_compression setting
+    pub fn from_db_setting(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Self::Gz),
+            "zstd" => Some(Self::Zst),
+            "xz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+
+    pub fn from_magic(magic: &[u8]) -> Self {
+        const MAGICS: [(&[u8], Compression); 6] = [
+            (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zst),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+            (&[0x1f, 0x8b], Compression::Gz),
+            (b"BZh", Compression::Bz2),
+            (&[0x04, 0x22, 0x4d, 0x18], Compression::Lz4),
+            (&[0x89, b'L', b'Z', b'O'], Compression::Lzo),
+        ];
+        MAGICS
+            .into_iter()
+            .find(|(m, _)| magic.starts_with(m))
+            .map_or(Self::None, |(_, compression)| compression)
+    }
+
+    /// Compression of a file from its first bytes
+    pub fn detect(file: &mut File) -> Result<Self, io::Error> {
+        let mut magic = Vec::with_capacity(6);
+        Read::by_ref(file).take(6).read_to_end(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self::from_magic(&magic))
+    }
+
+    /// Decompressed stream of a file, detected from its content
+    pub fn open(path: &Path) -> Result<Box<dyn Read>, io::Error> {
+        let mut file = File::open(path)?;
+        Self::detect(&mut file)?.decoder(file)
+    }
+
+    /// Compress data into out, only the db compressions
+    pub fn compress(&self, data: &[u8], mut out: impl Write) -> Result<(), io::Error> {
+        match self {
+            Self::None => out.write_all(data),
+            Self::Gz => {
+                let mut gz = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+                gz.write_all(data)?;
+                gz.finish().map(|_| ())
+            }
+            Self::Zst => {
+                ruzstd::encoding::compress(data, out, CompressionLevel::Fastest);
+                Ok(())
+            }
+            Self::Xz => {
+                let mut xz = XzWriter::new(out, XzOptions::with_preset(6))?;
+                xz.write_all(data)?;
+                xz.finish().map(|_| ())
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("Cannot compress with {:?}", self),
+            )),
+        }
+    }
+
+    /// Compressor makepkg needs, not in base-devel
+    pub fn package(&self) -> Option<&'static str> {
+        match self {
+            Self::Lz4 => Some("lz4"),
+            Self::Lzo => Some("lzop"),
+            _ => None,
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.extension() == extension)
+    }
+
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| name.ends_with(c.extension()))
+    }
+
+    /// Decompressed stream of the archive
+    pub fn decoder<'a>(&self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>, io::Error> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Zst => Box::new(StreamingDecoder::new(reader).map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData, format!("Zstd error: {}", e))
+            })?),
+            Self::Xz => Box::new(XzReader::new(reader, true)),
+            Self::Gz => Box::new(MultiGzDecoder::new(reader)),
+            Self::Bz2 => Box::new(MultiBzDecoder::new(reader)),
+            Self::Lz4 => Box::new(FrameDecoder::new(reader)),
+            Self::Lzo => Box::new(Cursor::new(lzop(reader)?)),
+        })
+    }
+}
+
+fn lzop(mut reader: impl Read) -> Result<Vec<u8>, io::Error> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let mut child = Command::new("lzop")
+        .args(["-d", "-c"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run lzop: {}", e)))?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let out = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| io::Error::other("lzop writer panicked"))??;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "lzop failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoders() {
+        assert_eq!(
+            Compression::from_file_name("vi-1:070224-6-x86_64.pkg.tar.zst"),
+            Some(Compression::Zst)
+        );
+        assert_eq!(
+            Compression::from_file_name("vi-1:070224-6-x86_64.pkg.tar"),
+            Some(Compression::None)
+        );
+        assert_eq!(Compression::from_file_name("vi.tar.zst"), None);
+        assert_eq!(
+            Compression::from_extension(".pkg.tar.xz"),
+            Some(Compression::Xz)
+        );
+
+        let data = b"package content".repeat(100);
+        let mut xz = XzWriter::new(Vec::new(), XzOptions::with_preset(6)).unwrap();
+        xz.write_all(&data).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&data).unwrap();
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bz2.write_all(&data).unwrap();
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        lz4.write_all(&data).unwrap();
+        for (compression, compressed) in [
+            (Compression::None, data.clone()),
+            (Compression::Xz, xz.finish().unwrap()),
+            (Compression::Gz, gz.finish().unwrap()),
+            (Compression::Bz2, bz2.finish().unwrap()),
+            (Compression::Lz4, lz4.finish().unwrap()),
+        ] {
+            let mut res = Vec::new();
+            compression
+                .decoder(compressed.as_slice())
+                .unwrap()
+                .read_to_end(&mut res)
+                .unwrap();
+            assert_eq!(res, data, "Testing {:?}", compression);
+            assert_eq!(Compression::from_magic(&compressed), compression);
+        }
+
+        for compression in [Compression::Gz, Compression::Zst, Compression::Xz] {
+            let mut compressed = Vec::new();
+            compression.compress(&data, &mut compressed).unwrap();
+            assert_eq!(Compression::from_magic(&compressed), compression);
+            let mut res = Vec::new();
+            compression
+                .decoder(compressed.as_slice())
+                .unwrap()
+                .read_to_end(&mut res)
+                .unwrap();
+            assert_eq!(res, data, "Testing {:?}", compression);
+        }
+    }
+}
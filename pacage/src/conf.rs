@@ -5,15 +5,25 @@ use serde::Deserialize;
 use std::fs::{self, create_dir_all};
 use std::path::{Path, PathBuf};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs::read_to_string,
 };
 use thiserror::Error;
 use toml::{Table, Value};
 
+use crate::binary_cache::BinaryCache;
+use crate::compression::Compression;
+use crate::format::MakepkgConf;
+use crate::utils::file_lock::FileLock;
+
 const DEFAULT_CONF_DIR: &str = "/etc/pacage";
+// Top level tables that are not packages
+const RESERVED_TABLES: &[&str] = &["makepkg", "profiles", "targets"];
+// Repo built when no target is declared (repo_name), its db is directly in repo/
+pub const DEFAULT_TARGET: &str = "pacage";
 const BUILD_SCRIPT_CONTENT: &str = std::include_str!("../../resources/build_pkg.sh");
-pub(crate) const BUILD_SCRIPT_FILE: &str = "pacage_build.sh";
+pub const BUILD_SCRIPT_FILE: &str = "pacage_build.sh";
+const MAKEPKG_CONF_CONTENT: &str = std::include_str!("../../resources/makepkg.conf");
 
 // pub const fn default_bool<const V: bool>() -> bool {
 //     V
@@ -90,6 +100,22 @@ pub struct Package {
     pub deps: Option<bool>,
     #[serde(default)]
     pub repo: Repo,
+    pub profile: Option<String>,
+    // USE flags, applied after the global ones
+    #[serde(rename = "use")]
+    pub use_flags: Option<Vec<String>>,
+    // Build environment variables
+    pub env: Option<BTreeMap<String, String>>,
+    // Two stages profile guided optimisation build
+    pub pgo: Option<Pgo>,
+    // Override the global pkgrel_suffix
+    pub pkgrel_suffix: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Pgo {
+    // Shell command run in a throw-away container with the instrumented package installed
+    pub train: String,
 }
 
 impl std::hash::Hash for Package {
@@ -105,92 +131,517 @@ impl std::cmp::PartialEq for Package {
 }
 impl std::cmp::Eq for Package {}
 
-fn write_value(file: &mut String, key: &str, value: Option<&String>, def: Option<&String>) {
-    if let Some(v) = value.or(def) {
-        file.push_str(&format!("{}=\"{}\"\n", key, v));
+// makepkg.conf variables that can be set from pacage.toml
+const MANAGED_VARS: &[&str] = &[
+    "PACKAGER",
+    "MAKEFLAGS",
+    "PKGEXT",
+    "CFLAGS",
+    "CXXFLAGS",
+    "LDFLAGS",
+    "LTOFLAGS",
+    "RUSTFLAGS",
+    "BUILDENV",
+    "OPTIONS",
+];
+
+// Items of a raw makepkg.conf array: "(strip !debug)" -> ["strip", "!debug"]
+fn array_items(raw: Option<String>) -> Vec<String> {
+    let Some(raw) = raw else {
+        return Vec::new();
+    };
+    raw.lines()
+        .flat_map(|line| {
+            line.split_whitespace()
+                .take_while(|item| !item.starts_with('#'))
+                .map(|item| item.trim_matches(|c| c == '(' || c == ')').to_string())
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Flags of a raw makepkg.conf value, `$VAR` are replaced by the already computed flags
+fn flags_tokens(raw: &str, computed: &HashMap<&str, Vec<String>>) -> Vec<String> {
+    let raw = raw.trim().replace("\\\n", " ");
+    let raw = raw
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .or_else(|| raw.strip_prefix('\'').and_then(|r| r.strip_suffix('\'')))
+        .unwrap_or(&raw);
+    raw.split_whitespace()
+        .flat_map(|token| {
+            let var = token
+                .strip_prefix("${")
+                .and_then(|t| t.strip_suffix('}'))
+                .or_else(|| token.strip_prefix('$'));
+            match var.and_then(|var| computed.get(var)) {
+                Some(flags) => flags.clone(),
+                None => vec![token.to_string()],
+            }
+        })
+        .collect()
+}
+
+// Enable ("lto") or disable ("!lto") an option, replacing its previous state
+fn toggle(items: &mut Vec<String>, item: &str) {
+    let name = item.trim_start_matches('!');
+    match items.iter_mut().find(|i| i.trim_start_matches('!') == name) {
+        Some(old) => *old = item.to_string(),
+        None => items.push(item.to_string()),
+    }
+}
+
+// First key of a table header: [vi.env] -> vi, ["vi"] -> vi
+fn header_key(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let inner = line.strip_prefix('[')?.trim_start_matches('[');
+    let key = inner.split([']', '.']).next()?.trim();
+    Some(key.trim_matches('"').trim_matches('\''))
+}
+
+/// pacage.toml content without the package tables ([<name>], [<name>.*]) or inline table
+/// (<name> = {..}), comments and formatting are kept. None if the package is not there.
+pub fn remove_package_table(content: &str, name: &str) -> Option<String> {
+    let mut res = String::new();
+    // No table header yet, the inline tables are there
+    let mut top_level = true;
+    let mut in_table = false;
+    // Comments and blank lines at the end of a removed table belong to the next one
+    let mut pending = String::new();
+    let mut found = false;
+    for line in content.split_inclusive('\n') {
+        if let Some(key) = header_key(line) {
+            top_level = false;
+            in_table = key == name;
+            if !in_table {
+                // Single blank line where the table was
+                if res.is_empty() || res.ends_with("\n\n") {
+                    res.push_str(pending.trim_start_matches(['\n', ' ', '\t']));
+                } else {
+                    res.push_str(&pending);
+                }
+            }
+            pending.clear();
+        } else if in_table {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                pending.push_str(line);
+            } else {
+                pending.clear();
+            }
+            continue;
+        } else if top_level
+            && line.split_once('=').is_some_and(|(key, value)| {
+                key.trim().trim_matches('"') == name && value.trim_start().starts_with('{')
+            })
+        {
+            found = true;
+            continue;
+        }
+        if in_table {
+            found = true;
+        } else {
+            res.push_str(line);
+        }
+    }
+    Some(res).filter(|_| found)
+}
+
+// Where the makepkg.conf used as base come from
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MakepkgBase {
+    // Template shipped with pacage
+    #[default]
+    Pacage,
+    // /etc/makepkg.conf of the builder image
+    Image,
+}
+
+impl TryFrom<&str> for MakepkgBase {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "pacage" => Ok(Self::Pacage),
+            "image" => Ok(Self::Image),
+            _ => Err("Invalid makepkg_base should be \"pacage\" or \"image\"".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Toolchain {
+    Gcc,
+    Clang,
+}
+
+impl Toolchain {
+    // (CC, CXX)
+    fn compilers(&self) -> (&'static str, &'static str) {
+        match self {
+            Toolchain::Gcc => ("gcc", "g++"),
+            Toolchain::Clang => ("clang", "clang++"),
+        }
+    }
+
+    // Packages to install in the builder, base-devel already has gcc
+    fn packages(&self) -> &'static [&'static str] {
+        match self {
+            Toolchain::Gcc => &[],
+            Toolchain::Clang => &["clang"],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Linker {
+    Bfd,
+    Gold,
+    Lld,
+    Mold,
+}
+
+impl Linker {
+    fn name(&self) -> &'static str {
+        match self {
+            Linker::Bfd => "bfd",
+            Linker::Gold => "gold",
+            Linker::Lld => "lld",
+            Linker::Mold => "mold",
+        }
+    }
+
+    // Packages to install in the builder, base-devel already has binutils
+    fn packages(&self) -> &'static [&'static str] {
+        match self {
+            Linker::Bfd | Linker::Gold => &[],
+            Linker::Lld => &["lld"],
+            Linker::Mold => &["mold"],
+        }
     }
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct Makepkg {
     packager: Option<String>,
+    // Package compression, ".pkg.tar.{zst,xz,gz,bz2,lz4,lzo}" or ".pkg.tar"
+    pkgext: Option<String>,
     cflags: Option<String>,
     cxxflags: Option<String>,
     rustflags: Option<String>,
     makeflags: Option<String>,
     ldflags: Option<String>,
     ltoflags: Option<String>,
+    // Token level edits of the flags, applied after the flags override of the same level
+    cflags_append: Option<String>,
+    cflags_remove: Option<String>,
+    cxxflags_append: Option<String>,
+    cxxflags_remove: Option<String>,
+    rustflags_append: Option<String>,
+    rustflags_remove: Option<String>,
+    ldflags_append: Option<String>,
+    ldflags_remove: Option<String>,
+    ltoflags_append: Option<String>,
+    ltoflags_remove: Option<String>,
     pub ccache: Option<bool>,
+    // Toggles on top of the makepkg.conf OPTIONS/BUILDENV, ex: ["!lto", "debug"]
+    options: Option<Vec<String>>,
+    buildenv: Option<Vec<String>>,
+    // false: build with --nocheck
+    check: Option<bool>,
+    // Exported as CC/CXX
+    toolchain: Option<Toolchain>,
+    // Added to LDFLAGS/RUSTFLAGS as -fuse-ld=
+    linker: Option<Linker>,
 }
 
+type FlagsEdit<'a> = (Option<&'a String>, Option<&'a String>, Option<&'a String>);
+
 impl Makepkg {
+    // (override, append, remove) of a makepkg.conf flags variable
+    fn flags(&self, var: &str) -> FlagsEdit<'_> {
+        match var {
+            "CFLAGS" => (
+                self.cflags.as_ref(),
+                self.cflags_append.as_ref(),
+                self.cflags_remove.as_ref(),
+            ),
+            "CXXFLAGS" => (
+                self.cxxflags.as_ref(),
+                self.cxxflags_append.as_ref(),
+                self.cxxflags_remove.as_ref(),
+            ),
+            "LDFLAGS" => (
+                self.ldflags.as_ref(),
+                self.ldflags_append.as_ref(),
+                self.ldflags_remove.as_ref(),
+            ),
+            "LTOFLAGS" => (
+                self.ltoflags.as_ref(),
+                self.ltoflags_append.as_ref(),
+                self.ltoflags_remove.as_ref(),
+            ),
+            "RUSTFLAGS" => (
+                self.rustflags.as_ref(),
+                self.rustflags_append.as_ref(),
+                self.rustflags_remove.as_ref(),
+            ),
+            _ => (None, None, None),
+        }
+    }
+
+    fn base_conf(conf: &Conf) -> Result<String, std::io::Error> {
+        match conf.makepkg_base {
+            MakepkgBase::Pacage => Ok(MAKEPKG_CONF_CONTENT.to_string()),
+            MakepkgBase::Image => {
+                let path = conf.image_makepkg_conf();
+                fs::read_to_string(&path).map_err(|e| {
+                    std::io::Error::new(
+                        e.kind(),
+                        format!(
+                            "{}: {}, it is copied from the image when the builder start",
+                            path.display(),
+                            e
+                        ),
+                    )
+                })
+            }
+        }
+    }
+
+    // Layers are applied in order: makepkg.conf, [makepkg], profiles (parents first), package
+    pub fn resolve(
+        conf: &Conf,
+        pkg: &Package,
+        target: &Target,
+    ) -> Result<ResolvedMakepkg, std::io::Error> {
+        let mut file = MakepkgConf::new(&Self::base_conf(conf)?);
+        let layers = conf.makepkg_layers(pkg, target);
+        let mut origins: BTreeMap<String, Vec<Origin>> = BTreeMap::new();
+        let mut add_origin = |var: &str, origin: &Origin, replace: bool| {
+            let origins = origins.entry(var.to_string()).or_default();
+            if replace {
+                origins.clear();
+            }
+            origins.push(origin.clone());
+        };
+        for var in MANAGED_VARS {
+            if file.get(var).is_some() {
+                add_origin(var, &Origin::Base, true);
+            }
+        }
+        for var in ["SRCDEST", "SRCPKGDEST"] {
+            file.set(var, &format!("/build/srcs/{}", pkg.name));
+            add_origin(var, &Origin::Pacage, true);
+        }
+        for var in ["PACKAGER", "MAKEFLAGS", "PKGEXT"] {
+            for (origin, makepkg) in &layers {
+                let value = match var {
+                    "PACKAGER" => makepkg.packager.as_ref(),
+                    "PKGEXT" => makepkg.pkgext.as_ref(),
+                    _ => makepkg.makeflags.as_ref(),
+                };
+                if let Some(ext) = value.filter(|_| var == "PKGEXT") {
+                    if Compression::from_extension(ext).is_none() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Unsupported pkgext \"{}\" in {}", ext, origin),
+                        ));
+                    }
+                }
+                if let Some(value) = value {
+                    file.set(var, &format!("\"{}\"", value));
+                    add_origin(var, origin, true);
+                }
+            }
+        }
+        let linker = layers
+            .iter()
+            .rev()
+            .find_map(|(origin, m)| Some((origin, m.linker?)));
+        // Same order as in makepkg.conf, CXXFLAGS can reference CFLAGS
+        let mut computed = HashMap::new();
+        for var in ["CFLAGS", "CXXFLAGS", "LDFLAGS", "LTOFLAGS", "RUSTFLAGS"] {
+            let mut flags = flags_tokens(&file.get(var).unwrap_or_default(), &computed);
+            let mut changed = false;
+            for (origin, makepkg) in &layers {
+                let (value, append, remove) = makepkg.flags(var);
+                if let Some(value) = value {
+                    flags = flags_tokens(value, &computed);
+                    add_origin(var, origin, true);
+                    changed = true;
+                }
+                if let Some(remove) = remove {
+                    let remove: Vec<&str> = remove.split_whitespace().collect();
+                    flags.retain(|flag| !remove.contains(&flag.as_str()));
+                }
+                if let Some(append) = append {
+                    flags.extend(append.split_whitespace().map(|f| f.to_string()));
+                }
+                if append.is_some() || remove.is_some() {
+                    add_origin(var, origin, false);
+                    changed = true;
+                }
+            }
+            let fuse_ld = match var {
+                "LDFLAGS" => "-fuse-ld=",
+                "RUSTFLAGS" => "-Clink-arg=-fuse-ld=",
+                _ => "",
+            };
+            if let Some((origin, linker)) = linker.filter(|_| !fuse_ld.is_empty()) {
+                flags.retain(|flag| !flag.starts_with(fuse_ld));
+                flags.push(format!("{}{}", fuse_ld, linker.name()));
+                add_origin(var, origin, false);
+                changed = true;
+            }
+            // Untouched values are kept as is
+            if changed {
+                file.set(var, &format!("\"{}\"", flags.join(" ")));
+            }
+            computed.insert(var, flags);
+        }
+        let mut buildenv = array_items(file.get("BUILDENV"));
+        let mut options = array_items(file.get("OPTIONS"));
+        for (origin, makepkg) in &layers {
+            match makepkg.ccache {
+                Some(true) => toggle(&mut buildenv, "ccache"),
+                Some(false) => toggle(&mut buildenv, "!ccache"),
+                None => {}
+            }
+            for item in makepkg.buildenv.iter().flatten() {
+                toggle(&mut buildenv, item);
+            }
+            if makepkg.ccache.is_some() || makepkg.buildenv.is_some() {
+                add_origin("BUILDENV", origin, false);
+            }
+            for item in makepkg.options.iter().flatten() {
+                toggle(&mut options, item);
+            }
+            if makepkg.options.is_some() {
+                add_origin("OPTIONS", origin, false);
+            }
+        }
+        // Keep the makepkg defaults when there is nothing to set
+        if file.get("BUILDENV").is_some() || !buildenv.is_empty() {
+            file.set("BUILDENV", &format!("({})", buildenv.join(" ")));
+        }
+        if file.get("OPTIONS").is_some() || !options.is_empty() {
+            file.set("OPTIONS", &format!("({})", options.join(" ")));
+        }
+        let mut flags = Vec::new();
+        if let Some((origin, _)) = layers.iter().rev().find(|(_, m)| m.check.is_some()) {
+            add_origin("check", origin, true);
+            if layers.iter().rev().find_map(|(_, m)| m.check) == Some(false) {
+                flags.push("--nocheck");
+            }
+        }
+        let mut env = BTreeMap::new();
+        let mut packages = Vec::new();
+        if let Some((origin, toolchain)) = layers
+            .iter()
+            .rev()
+            .find_map(|(origin, m)| Some((origin, m.toolchain?)))
+        {
+            let (cc, cxx) = toolchain.compilers();
+            for (var, value) in [("CC", cc), ("CXX", cxx)] {
+                env.insert(var.to_string(), value.to_string());
+                add_origin(var, origin, true);
+            }
+            packages.extend(toolchain.packages());
+        }
+        if let Some((_, linker)) = linker {
+            packages.extend(linker.packages());
+        }
+        // Compressors missing from the builder image
+        if let Some(ext) = layers.iter().rev().find_map(|(_, m)| m.pkgext.as_deref()) {
+            packages.extend(Compression::from_extension(ext).and_then(|c| c.package()));
+        }
+        for (var, value) in pkg.env.iter().flatten() {
+            env.insert(var.clone(), value.clone());
+            add_origin(var, &Origin::Env(pkg.name.clone()), true);
+        }
+        Ok(ResolvedMakepkg {
+            file,
+            flags,
+            origins,
+            env,
+            packages,
+        })
+    }
+
     pub fn get_conf_file(
         conf: &Conf,
-        makepkg: Option<&Makepkg>,
-        name: &str,
+        pkg: &Package,
+        target: &Target,
     ) -> Result<String, std::io::Error> {
-        // TODO: use env instead
-        let mut file = std::fs::read_to_string("/etc/makepkg.conf")?;
-        let def = conf.makepkg.as_ref();
-        file.push('\n');
-        file.push_str(&format!("SRCDEST=/build/srcs/{}\n", name));
-        file.push_str(&format!("SRCPKGDEST==/build/srcs/{}\n", name));
-        // file.push_str(&format!("SRCDEST=/build/srcs/{}\n", name));
-        // file.push_str("PKGDEST=/build/repo/\n");
-        write_value(
-            &mut file,
-            "PACKAGER",
-            makepkg.map(|a| a.packager.as_ref()).flatten(),
-            def.map(|a| a.packager.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "MAKEFLAGS",
-            makepkg.map(|a| a.makeflags.as_ref()).flatten(),
-            def.map(|a| a.makeflags.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "CFLAGS",
-            makepkg.map(|a| a.cflags.as_ref()).flatten(),
-            def.map(|a| a.cflags.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "CXXFLAGS",
-            makepkg.map(|a| a.cxxflags.as_ref()).flatten(),
-            def.map(|a| a.cxxflags.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "RUSTFLAGS",
-            makepkg.map(|a| a.rustflags.as_ref()).flatten(),
-            def.map(|a| a.rustflags.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "LDFLAGS",
-            makepkg.map(|a| a.ldflags.as_ref()).flatten(),
-            def.map(|a| a.ldflags.as_ref()).flatten(),
-        );
-        write_value(
-            &mut file,
-            "LTOFLAGS",
-            makepkg.map(|a| a.ltoflags.as_ref()).flatten(),
-            def.map(|a| a.ltoflags.as_ref()).flatten(),
-        );
-        if makepkg
-            .as_ref()
-            .map(|c| c.ccache)
-            .unwrap_or(Some(def.is_some_and(|d| d.ccache.is_some_and(|d| d))))
-            .is_some_and(|a| a)
-        {
-            file.push_str("BUILDENV=(!distcc color ccache check !sign)\n");
+        Ok(Self::resolve(conf, pkg, target)?.file.to_string())
+    }
+}
+
+// Where a makepkg setting come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    // Base makepkg.conf
+    Base,
+    // Forced by pacage
+    Pacage,
+    Global,
+    Profile(String),
+    Package(String),
+    Env(String),
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Base => write!(f, "makepkg.conf"),
+            Origin::Pacage => write!(f, "pacage"),
+            Origin::Global => write!(f, "[makepkg]"),
+            Origin::Profile(name) => write!(f, "[profiles.{}]", name),
+            Origin::Package(name) => write!(f, "[{}.makepkg]", name),
+            Origin::Env(name) => write!(f, "[{}.env]", name),
+        }
+    }
+}
+
+pub struct ResolvedMakepkg {
+    pub file: MakepkgConf,
+    // Extra makepkg command line flags
+    pub flags: Vec<&'static str>,
+    // Layers that set or modified each setting, in application order
+    pub origins: BTreeMap<String, Vec<Origin>>,
+    // Build environment, toolchain then [<pkg>.env]
+    pub env: BTreeMap<String, String>,
+    // Toolchain packages to install before the build
+    pub packages: Vec<&'static str>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub inherits: Option<String>,
+    #[serde(flatten)]
+    pub makepkg: Makepkg,
+}
+
+// Output repo, every package is built once per target
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Target {
+    // Repo name, set just after serialization
+    #[serde(default = "default_name")]
+    pub name: String,
+    // Applied after [makepkg], before the package settings
+    pub profile: Option<String>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_TARGET.to_string(),
+            profile: None,
         }
-        file.push_str(
-            "OPTIONS=(strip docs !libtool !staticlibs emptydirs zipman purge !debug lto)\n",
-        );
-        Ok(file)
     }
 }
 
@@ -208,11 +659,36 @@ pub struct Conf {
     pub packages: HashSet<Package>,
     // TODO: container_runner: (podman, docker...)
     pub makepkg: Option<Makepkg>,
+    pub makepkg_base: MakepkgBase,
+    pub profiles: HashMap<String, Profile>,
+    // Global USE flags
+    pub use_flags: Vec<String>,
+    // Built packages pkgrel is <pkgrel>.<suffix>
+    pub pkgrel_suffix: Option<u32>,
+    // Packages looked up by build inputs before building, uploaded after
+    pub binary_cache: Option<BinaryCache>,
+    // Packages and dbs signing key, gpg keyring dir (default: the user one)
+    pub gpg_key: Option<String>,
+    pub gpg_home: Option<PathBuf>,
+    // Replaced package files kept per package in <repo>/archive/, for rollbacks
+    pub keep_versions: usize,
+    // Name of the default target, the only one when none is declared
+    pub repo_name: String,
+    // gzip (default), zstd or xz
+    pub db_compression: Compression,
+    // Never empty, the repo_name target first: the default one
+    pub targets: Vec<Target>,
 
     pub max_par_dl: usize,
 
     // Never serialized.
     pub resolver: HashMap<String, String>,
+    // Set from the command line (-f)
+    pub force_rebuild: bool,
+    // Set from the command line (--allow-downgrade), replace a newer version in the repo
+    pub allow_downgrade: bool,
+    // Set from the command line (--wait), wait for the locks held by another instance
+    pub wait_lock: bool,
 }
 
 #[cfg_attr(test, bon)]
@@ -305,6 +781,16 @@ impl Conf {
             Some(Value::Boolean(deps)) => *deps,
             Some(a) => Err(ConfError::Format(format!("Invalid \"deps\": {:?}", a)))?,
         };
+        let makepkg_base = match g.get("makepkg_base") {
+            None => MakepkgBase::default(),
+            Some(Value::String(base)) => {
+                MakepkgBase::try_from(base.as_str()).map_err(ConfError::Format)?
+            }
+            Some(a) => Err(ConfError::Format(format!(
+                "Invalid \"makepkg_base\": {:?}",
+                a
+            )))?,
+        };
         let makepkg: Option<Makepkg> = match g.get("makepkg") {
             None => None,
             Some(Value::Table(makepkg)) => Some(
@@ -314,8 +800,116 @@ impl Conf {
             ),
             Some(a) => Err(ConfError::Format(format!("Invalid \"makepkg\": {:?}", a)))?,
         };
+        let use_flags = match g.get("use") {
+            None => Vec::new(),
+            Some(Value::Array(flags)) => flags
+                .iter()
+                .map(|flag| match flag {
+                    Value::String(flag) => Ok(flag.clone()),
+                    a => Err(ConfError::Format(format!("Invalid \"use\" flag: {:?}", a))),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(a) => Err(ConfError::Format(format!("Invalid \"use\": {:?}", a)))?,
+        };
+        let pkgrel_suffix = match g.get("pkgrel_suffix") {
+            None => None,
+            Some(Value::Integer(suffix)) => Some(u32::try_from(*suffix).map_err(|_| {
+                ConfError::Format(format!("Invalid \"pkgrel_suffix\": {}", suffix))
+            })?),
+            Some(a) => Err(ConfError::Format(format!(
+                "Invalid \"pkgrel_suffix\": {:?}",
+                a
+            )))?,
+        };
+        let binary_cache = match g.get("binary_cache") {
+            None => None,
+            Some(Value::String(cache)) => Some(BinaryCache::new(cache)),
+            Some(a) => Err(ConfError::Format(format!(
+                "Invalid \"binary_cache\": {:?}",
+                a
+            )))?,
+        };
+        let gpg_key = match g.get("gpg_key") {
+            None => None,
+            Some(Value::String(key)) => Some(key.clone()),
+            Some(a) => Err(ConfError::Format(format!("Invalid \"gpg_key\": {:?}", a)))?,
+        };
+        let gpg_home = match g.get("gpg_home") {
+            None => None,
+            Some(Value::String(home)) => Some(PathBuf::from(home)),
+            Some(a) => Err(ConfError::Format(format!("Invalid \"gpg_home\": {:?}", a)))?,
+        };
+        let keep_versions = match g.get("keep_versions") {
+            None => 0,
+            Some(Value::Integer(keep)) => usize::try_from(*keep)
+                .map_err(|_| ConfError::Format(format!("Invalid \"keep_versions\": {}", keep)))?,
+            Some(a) => Err(ConfError::Format(format!(
+                "Invalid \"keep_versions\": {:?}",
+                a
+            )))?,
+        };
+        let repo_name = match g.get("repo_name") {
+            None => DEFAULT_TARGET.to_string(),
+            Some(Value::String(name)) if !name.is_empty() && !name.contains('/') => name.clone(),
+            Some(a) => Err(ConfError::Format(format!("Invalid \"repo_name\": {:?}", a)))?,
+        };
+        let db_compression = match g.get("db_compression") {
+            None => Compression::Gz,
+            Some(Value::String(name)) => Compression::from_db_setting(name).ok_or_else(|| {
+                ConfError::Format(format!(
+                    "Invalid \"db_compression\": {}, expected gzip, zstd or xz",
+                    name
+                ))
+            })?,
+            Some(a) => Err(ConfError::Format(format!(
+                "Invalid \"db_compression\": {:?}",
+                a
+            )))?,
+        };
+        let mut profiles = HashMap::new();
+        match g.get("profiles") {
+            None => {}
+            Some(Value::Table(t)) => {
+                for (name, v) in t {
+                    let profile = v.clone().try_into::<Profile>().map_err(|e| {
+                        ConfError::Format(format!("Failed to parse, profiles.{}: {}", name, e))
+                    })?;
+                    profiles.insert(name.to_string(), profile);
+                }
+            }
+            Some(a) => Err(ConfError::Format(format!("Invalid \"profiles\": {:?}", a)))?,
+        }
+        let mut targets = Vec::new();
+        match g.get("targets") {
+            None => targets.push(Target {
+                name: repo_name.clone(),
+                profile: None,
+            }),
+            Some(Value::Table(t)) => {
+                for (name, v) in t {
+                    let mut target = v.clone().try_into::<Target>().map_err(|e| {
+                        ConfError::Format(format!("Failed to parse, targets.{}: {}", name, e))
+                    })?;
+                    target.name = name.to_string();
+                    targets.push(target);
+                }
+                // The table is sorted by name, the default one is the repo_name target
+                let default = targets
+                    .iter()
+                    .position(|target| target.name == repo_name)
+                    .ok_or_else(|| {
+                        ConfError::Format(format!(
+                            "No \"targets.{}\", the default target is named after \"repo_name\"",
+                            repo_name
+                        ))
+                    })?;
+                let default = targets.remove(default);
+                targets.insert(0, default);
+            }
+            Some(a) => Err(ConfError::Format(format!("Invalid \"targets\": {:?}", a)))?,
+        }
         for (name, v) in g {
-            if name.as_str() != "makepkg" {
+            if !RESERVED_TABLES.contains(&name.as_str()) {
                 if let Value::Table(t) = v {
                     match t.try_into::<Package>() {
                         Ok(mut p) => {
@@ -327,14 +921,29 @@ impl Conf {
                 }
             }
         }
+        Self::check_profiles(&profiles, &packages, &targets)?;
         let resolver = Self::parse_resolver(&conf_dir);
         Ok(Self {
             resolver,
+            force_rebuild: false,
+            allow_downgrade: false,
+            wait_lock: false,
             container_runner,
             server_dir,
             conf_dir,
             host_server_dir,
             makepkg,
+            makepkg_base,
+            profiles,
+            use_flags,
+            pkgrel_suffix,
+            binary_cache,
+            gpg_key,
+            gpg_home,
+            keep_versions,
+            repo_name,
+            db_compression,
+            targets,
             build_log_dir,
             deps,
             packages,
@@ -342,6 +951,97 @@ impl Conf {
         })
     }
 
+    // Every profile and its parents should exist, without inheritance cycle
+    fn check_profiles(
+        profiles: &HashMap<String, Profile>,
+        packages: &HashSet<Package>,
+        targets: &[Target],
+    ) -> Result<(), ConfError> {
+        for name in profiles.keys() {
+            let mut chain = vec![name.as_str()];
+            let mut next = profiles[name].inherits.as_deref();
+            while let Some(parent) = next {
+                if chain.contains(&parent) {
+                    chain.push(parent);
+                    Err(ConfError::Format(format!(
+                        "Profile inheritance cycle: {}",
+                        chain.join(" -> ")
+                    )))?
+                }
+                let Some(profile) = profiles.get(parent) else {
+                    Err(ConfError::Format(format!(
+                        "Profile \"{}\" inherits from unknown profile \"{}\"",
+                        chain.last().unwrap_or(&""),
+                        parent
+                    )))?
+                };
+                chain.push(parent);
+                next = profile.inherits.as_deref();
+            }
+        }
+        for pkg in packages {
+            if let Some(profile) = &pkg.profile {
+                if !profiles.contains_key(profile) {
+                    Err(ConfError::Format(format!(
+                        "[{}] Unknown profile \"{}\"",
+                        pkg.name, profile
+                    )))?
+                }
+            }
+        }
+        for target in targets {
+            if let Some(profile) = &target.profile {
+                if !profiles.contains_key(profile) {
+                    Err(ConfError::Format(format!(
+                        "Target \"{}\" uses unknown profile \"{}\"",
+                        target.name, profile
+                    )))?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Profile and its parents, parents first
+    fn profile_chain<'a>(&'a self, profile: Option<&'a str>) -> Vec<(Origin, &'a Makepkg)> {
+        let mut profiles = Vec::new();
+        let mut next = profile;
+        // Cycles and missing profiles are rejected by Conf::new
+        while let Some(name) = next {
+            let Some(profile) = self.profiles.get(name) else {
+                break;
+            };
+            if profiles.iter().any(|(n, _)| *n == name) {
+                break;
+            }
+            profiles.push((name, profile));
+            next = profile.inherits.as_deref();
+        }
+        profiles
+            .into_iter()
+            .rev()
+            .map(|(name, profile)| (Origin::Profile(name.to_string()), &profile.makepkg))
+            .collect()
+    }
+
+    // Makepkg settings applied to a package, lowest priority first
+    pub fn makepkg_layers<'a>(
+        &'a self,
+        pkg: &'a Package,
+        target: &'a Target,
+    ) -> Vec<(Origin, &'a Makepkg)> {
+        let mut layers = Vec::new();
+        if let Some(makepkg) = &self.makepkg {
+            layers.push((Origin::Global, makepkg));
+        }
+        layers.extend(self.profile_chain(target.profile.as_deref()));
+        layers.extend(self.profile_chain(pkg.profile.as_deref()));
+        if let Some(makepkg) = &pkg.makepkg {
+            layers.push((Origin::Package(pkg.name.clone()), makepkg));
+        }
+        layers
+    }
+
     // Directory containing the pkgbuild
     pub fn pkg_dir(&self, pkg: &str) -> PathBuf {
         self.server_dir.join("pkgs").join(pkg)
@@ -356,16 +1056,91 @@ impl Conf {
         self.server_dir.join("srcs").join(pkg)
     }
 
-    pub fn get_repo_db(&self) -> PathBuf {
-        self.server_dir.join("repo").join("pacage.db.tar.gz")
+    // Collected profiles of a PGO build, relative to the server dir
+    pub fn pgo_subdir(&self, pkg: &str, target: &Target) -> PathBuf {
+        Path::new("cache").join("pgo").join(pkg).join(&target.name)
+    }
+
+    // makepkg.conf of the builder image, copied when the builder start
+    pub fn image_makepkg_conf(&self) -> PathBuf {
+        self.server_dir.join("cache").join("makepkg.conf")
+    }
+
+    // Sonames of the scanned package files of the target repo, see revdep
+    pub fn revdep_cache(&self, target: &Target) -> PathBuf {
+        self.server_dir
+            .join("cache")
+            .join("revdep")
+            .join(&target.name)
+    }
+
+    pub fn target(&self, name: Option<&str>) -> Result<&Target, ConfError> {
+        match name {
+            None => Ok(&self.targets[0]),
+            Some(name) => self
+                .targets
+                .iter()
+                .find(|t| t.name == name)
+                .ok_or_else(|| ConfError::Format(format!("Unknown target \"{}\"", name))),
+        }
+    }
+
+    // Directory of the target repo, relative to the server dir
+    pub fn repo_subdir(&self, target: &Target) -> PathBuf {
+        if target.name == self.repo_name {
+            PathBuf::from("repo")
+        } else {
+            Path::new("repo").join(&target.name)
+        }
+    }
+
+    pub fn repo_dir(&self, target: &Target) -> PathBuf {
+        self.server_dir.join(self.repo_subdir(target))
+    }
+
+    // Replaced package files of the target repo
+    pub fn archive_dir(&self, target: &Target) -> PathBuf {
+        self.repo_dir(target).join("archive")
+    }
+
+    // pacman entry point, a link to the db archive (repo_archive)
+    pub fn get_repo_db(&self, target: &Target) -> PathBuf {
+        self.repo_dir(target).join(format!("{}.db", target.name))
+    }
+
+    pub fn get_repo_files_db(&self, target: &Target) -> PathBuf {
+        self.repo_dir(target).join(format!("{}.files", target.name))
     }
 
-    pub fn get_repo_files_db(&self) -> PathBuf {
-        self.server_dir.join("repo").join("pacage.files.tar.gz")
+    // <repo>.db -> <repo>.db.tar.gz with db_compression
+    pub fn repo_archive(&self, db: &Path) -> PathBuf {
+        let mut archive = db.as_os_str().to_owned();
+        archive.push(".tar");
+        archive.push(self.db_compression.suffix());
+        PathBuf::from(archive)
+    }
+
+    /// Remove the package from pacage.toml, false if it is not declared there
+    pub fn remove_from_file(&self, pkg: &str) -> Result<bool, std::io::Error> {
+        let path = self.conf_dir.join("pacage.toml");
+        match remove_package_table(&read_to_string(&path)?, pkg) {
+            Some(content) => fs::write(path, content).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Lock the sources of the package against the other instances
+    pub fn lock_src(&self, pkg: &str) -> Result<FileLock, std::io::Error> {
+        let srcs = self.server_dir.join("srcs");
+        fs::create_dir_all(&srcs)?;
+        FileLock::new(&srcs.join(format!("{}.lock", pkg)), self.wait_lock)
     }
 
     pub fn remove_src(&self, pkg: &str) {
-        if let Err(e) = fs::remove_dir_all(self.pkg_src(pkg)) {
+        let res = self
+            .lock_src(pkg)
+            .and_then(|_lock| fs::remove_dir_all(self.pkg_src(pkg)));
+        if let Err(e) = res {
             error!("[{}] could not remove src dir: {}", pkg, e);
         }
     }
@@ -384,11 +1159,27 @@ impl Conf {
             makepkg: None,
             deps: None,
             repo: Repo::None,
+            profile: None,
+            use_flags: None,
+            env: None,
+            pgo: None,
+            pkgrel_suffix: None,
         };
         // self.packages.
         self.packages.insert(new);
     }
 
+    pub fn pkgrel_suffix(&self, name: &str) -> Option<u32> {
+        self.find(name)
+            .and_then(|p| p.pkgrel_suffix)
+            .or(self.pkgrel_suffix)
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Package> {
+        let name = self.resolver.get(name).map(|a| a.as_str()).unwrap_or(name);
+        self.packages.iter().find(|p| p.name == name)
+    }
+
     // Name should not be used after this call, but pkg.name
     pub fn get(&self, name: &str) -> &Package {
         let name = self.resolver.get(name).map(|a| a.as_str()).unwrap_or(name);
@@ -413,14 +1204,18 @@ impl Conf {
             create_dir_all(build_log_dir)
                 .map_err(|e| format!("Failed to create log dir: {}", e))?;
         }
-        create_dir_all(self.server_dir.join("repo"))
-            .map_err(|e| format!("Failed to create repo dir: {}", e))?;
+        for target in &self.targets {
+            create_dir_all(self.repo_dir(target))
+                .map_err(|e| format!("Failed to create {} repo dir: {}", target.name, e))?;
+        }
         create_dir_all(self.server_dir.join("cache").join("pacman"))
             .map_err(|e| format!("Failed to create cache dir: {}", e))?;
         if self
             .makepkg
-            .as_ref()
-            .is_some_and(|makepkg| makepkg.ccache.is_some_and(|a| a))
+            .iter()
+            .chain(self.profiles.values().map(|p| &p.makepkg))
+            .chain(self.packages.iter().filter_map(|p| p.makepkg.as_ref()))
+            .any(|makepkg| makepkg.ccache.is_some_and(|a| a))
         {
             create_dir_all(self.server_dir.join("cache").join("ccache"))
                 .map_err(|e| format!("Failed to create ccache dir: {}", e))?;
@@ -458,11 +1253,25 @@ impl Conf {
             packages: HashSet::new(),
             // TODO: container_runner: (podman, docker...)
             makepkg: None,
+            makepkg_base: MakepkgBase::default(),
+            profiles: HashMap::new(),
+            use_flags: Vec::new(),
+            pkgrel_suffix: None,
+            binary_cache: None,
+            gpg_key: None,
+            gpg_home: None,
+            keep_versions: 0,
+            repo_name: DEFAULT_TARGET.to_string(),
+            db_compression: Compression::Gz,
+            targets: vec![Target::default()],
 
             max_par_dl: 5,
 
             // Never serialized.
             resolver: resolver.unwrap_or(HashMap::new()),
+            force_rebuild: false,
+            allow_downgrade: false,
+            wait_lock: false,
         }
     }
 
@@ -496,13 +1305,27 @@ impl Conf {
             conf_dir: PathBuf::from("."),
             packages: HashSet::new(),
             makepkg: None,
+            makepkg_base: MakepkgBase::default(),
+            profiles: HashMap::new(),
+            use_flags: Vec::new(),
+            pkgrel_suffix: None,
+            binary_cache: None,
+            gpg_key: None,
+            gpg_home: None,
+            keep_versions: 0,
+            repo_name: DEFAULT_TARGET.to_string(),
+            db_compression: Compression::Gz,
+            targets: vec![Target::default()],
             resolver: HashMap::new(),
+            force_rebuild: false,
+            allow_downgrade: false,
+            wait_lock: false,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::env;
 
@@ -523,4 +1346,405 @@ mod tests {
             tmp_server_dir
         }
     }
+
+    fn package(makepkg: Makepkg) -> Package {
+        Package {
+            name: "foo".to_string(),
+            makepkg: Some(makepkg),
+            deps: None,
+            repo: Repo::None,
+            profile: None,
+            use_flags: None,
+            env: None,
+            pgo: None,
+            pkgrel_suffix: None,
+        }
+    }
+
+    fn resolve(conf: &Conf, makepkg: &Makepkg) -> ResolvedMakepkg {
+        Makepkg::resolve(conf, &package(makepkg.clone()), &Target::default()).unwrap()
+    }
+
+    #[test]
+    fn makepkg_conf() {
+        let mut conf = Conf::rand();
+        conf.makepkg = Some(Makepkg {
+            packager: Some("global".to_string()),
+            cflags: Some("-O2".to_string()),
+            ..Default::default()
+        });
+        let pkg = Makepkg {
+            packager: Some("pkg".to_string()),
+            ccache: Some(true),
+            ..Default::default()
+        };
+        assert!(resolve(&conf, &pkg).flags.is_empty());
+        let file = resolve(&conf, &pkg).file;
+        assert_eq!(file.get("SRCDEST").as_deref(), Some("/build/srcs/foo"));
+        assert_eq!(file.get("SRCPKGDEST").as_deref(), Some("/build/srcs/foo"));
+        assert_eq!(file.get("PACKAGER").as_deref(), Some("\"pkg\""));
+        assert_eq!(file.get("CFLAGS").as_deref(), Some("\"-O2\""));
+        assert_eq!(
+            file.get("BUILDENV").as_deref(),
+            Some("(!distcc color ccache check !sign)")
+        );
+        assert_eq!(
+            file.get("OPTIONS").as_deref(),
+            Some("(strip docs !libtool !staticlibs emptydirs zipman purge !debug lto)")
+        );
+    }
+
+    #[test]
+    fn makepkg_pkgext() {
+        let conf = Conf::rand();
+        let pkg = Makepkg {
+            pkgext: Some(".pkg.tar.xz".to_string()),
+            ..Default::default()
+        };
+        let file = resolve(&conf, &pkg).file;
+        assert_eq!(file.get("PKGEXT").as_deref(), Some("\".pkg.tar.xz\""));
+        let invalid = Makepkg {
+            pkgext: Some(".tar.xz".to_string()),
+            ..Default::default()
+        };
+        assert!(Makepkg::resolve(&conf, &package(invalid), &Target::default()).is_err());
+    }
+
+    #[test]
+    fn makepkg_toggles() {
+        let mut conf = Conf::rand();
+        conf.makepkg = Some(Makepkg {
+            ccache: Some(true),
+            options: Some(vec!["!lto".to_string(), "debug".to_string()]),
+            buildenv: Some(vec!["!color".to_string()]),
+            check: Some(false),
+            ..Default::default()
+        });
+        let pkg = Makepkg {
+            ccache: Some(false),
+            options: Some(vec!["!strip".to_string(), "lto".to_string()]),
+            buildenv: Some(vec!["distcc".to_string()]),
+            ..Default::default()
+        };
+        let file = resolve(&conf, &pkg).file;
+        assert_eq!(
+            file.get("BUILDENV").as_deref(),
+            Some("(distcc !color !ccache check !sign)")
+        );
+        assert_eq!(
+            file.get("OPTIONS").as_deref(),
+            Some("(!strip docs !libtool !staticlibs emptydirs zipman purge debug lto)")
+        );
+        assert_eq!(resolve(&conf, &pkg).flags, ["--nocheck"]);
+        let pkg = Makepkg {
+            check: Some(true),
+            ..Default::default()
+        };
+        assert!(resolve(&conf, &pkg).flags.is_empty());
+    }
+
+    fn append_remove_conf() -> Conf {
+        let mut conf = Conf::rand();
+        conf.makepkg = Some(Makepkg {
+            cflags_append: Some("-fno-omit-frame-pointer".to_string()),
+            ltoflags: Some("-flto=thin".to_string()),
+            ..Default::default()
+        });
+        conf
+    }
+
+    #[test]
+    fn makepkg_append_remove() {
+        let conf = append_remove_conf();
+        let pkg = Makepkg {
+            cflags_remove: Some("-fno-plt -pipe".to_string()),
+            cflags_append: Some("-O3".to_string()),
+            ltoflags_remove: Some("-flto=thin".to_string()),
+            ..Default::default()
+        };
+        let file = resolve(&conf, &pkg).file;
+        let cflags = "-march=x86-64 -mtune=generic -O2 -fexceptions \
+            -Wp,-D_FORTIFY_SOURCE=3 -Wformat -Werror=format-security \
+            -fstack-clash-protection -fcf-protection \
+            -fno-omit-frame-pointer -mno-omit-leaf-frame-pointer \
+            -fno-omit-frame-pointer -O3";
+        assert_eq!(file.get("CFLAGS"), Some(format!("\"{}\"", cflags)));
+        // Untouched, still use $CFLAGS
+        assert_eq!(
+            file.get("CXXFLAGS").as_deref(),
+            Some("\"$CFLAGS -Wp,-D_GLIBCXX_ASSERTIONS\"")
+        );
+        assert_eq!(file.get("LTOFLAGS").as_deref(), Some("\"\""));
+        let pkg = Makepkg {
+            cflags: Some("-O1".to_string()),
+            cxxflags_remove: Some("-Wp,-D_GLIBCXX_ASSERTIONS".to_string()),
+            ..Default::default()
+        };
+        let file = resolve(&conf, &pkg).file;
+        assert_eq!(file.get("CFLAGS").as_deref(), Some("\"-O1\""));
+        assert_eq!(file.get("CXXFLAGS").as_deref(), Some("\"-O1\""));
+    }
+
+    #[test]
+    fn makepkg_base_image() {
+        let mut conf = append_remove_conf();
+        let pkg = Makepkg {
+            cflags: Some("-O1".to_string()),
+            cxxflags_remove: Some("-Wp,-D_GLIBCXX_ASSERTIONS".to_string()),
+            ..Default::default()
+        };
+        conf.makepkg_base = MakepkgBase::Image;
+        conf.server_dir = mktemp();
+        assert!(Makepkg::resolve(&conf, &package(Makepkg::default()), &Target::default()).is_err());
+        fs::create_dir(conf.server_dir.join("cache")).unwrap();
+        fs::write(
+            conf.image_makepkg_conf(),
+            "OPTIONS=(debug)\nCFLAGS=\"-O2\"\n",
+        )
+        .unwrap();
+        let file = resolve(&conf, &pkg).file;
+        assert_eq!(file.get("OPTIONS").as_deref(), Some("(debug)"));
+        assert_eq!(file.get("BUILDENV"), None);
+        assert_eq!(file.get("CFLAGS").as_deref(), Some("\"-O1\""));
+        assert_eq!(file.get("CXXFLAGS").as_deref(), Some("\"\""));
+    }
+
+    fn conf_from(toml: &str) -> Result<Conf, ConfError> {
+        let conf_dir = mktemp();
+        fs::write(conf_dir.join("pacage.toml"), toml).unwrap();
+        Conf::new(conf_dir.to_str())
+    }
+
+    #[test]
+    fn profiles() {
+        let conf = conf_from(
+            r#"
+[makepkg]
+packager = "global"
+cflags = "-O2"
+
+[profiles.native-o3]
+cflags = "-march=native -O3"
+options = ["!debug"]
+
+[profiles.hardened]
+inherits = "native-o3"
+cflags_append = "-fstack-protector-strong"
+options = ["debug"]
+check = false
+
+[vi]
+profile = "hardened"
+[vi.makepkg]
+cflags_remove = "-O3"
+
+[linux]
+"#,
+        )
+        .unwrap();
+        assert_eq!(conf.packages.len(), 2);
+        let resolved = Makepkg::resolve(&conf, conf.get("vi"), &conf.targets[0]).unwrap();
+        assert_eq!(
+            resolved.file.get("CFLAGS").as_deref(),
+            Some("\"-march=native -fstack-protector-strong\"")
+        );
+        assert_eq!(resolved.file.get("PACKAGER").as_deref(), Some("\"global\""));
+        assert_eq!(resolved.flags, ["--nocheck"]);
+        let profile = |name: &str| Origin::Profile(name.to_string());
+        assert_eq!(
+            resolved.origins["CFLAGS"],
+            [
+                profile("native-o3"),
+                profile("hardened"),
+                Origin::Package("vi".to_string())
+            ]
+        );
+        assert_eq!(
+            resolved.origins["OPTIONS"],
+            [Origin::Base, profile("native-o3"), profile("hardened")]
+        );
+        assert_eq!(resolved.origins["PACKAGER"], [Origin::Global]);
+        assert_eq!(resolved.origins["check"], [profile("hardened")]);
+        assert!(resolved
+            .file
+            .get("OPTIONS")
+            .is_some_and(|o| o.contains(" debug ")));
+
+        let resolved = Makepkg::resolve(&conf, conf.get("linux"), &conf.targets[0]).unwrap();
+        assert_eq!(resolved.file.get("CFLAGS").as_deref(), Some("\"-O2\""));
+        assert_eq!(resolved.origins["CFLAGS"], [Origin::Global]);
+        assert!(resolved.flags.is_empty());
+
+        for toml in [
+            "[profiles.a]\ninherits = \"b\"\n[profiles.b]\ninherits = \"a\"\n",
+            "[profiles.a]\ninherits = \"missing\"\n",
+            "[vi]\nprofile = \"missing\"\n",
+        ] {
+            assert!(
+                matches!(conf_from(toml), Err(ConfError::Format(_))),
+                "{}",
+                toml
+            );
+        }
+    }
+
+    #[test]
+    fn toolchain_env() {
+        let conf = conf_from(
+            r#"
+[makepkg]
+ldflags = "-Wl,-O1 -fuse-ld=bfd"
+toolchain = "clang"
+linker = "lld"
+
+[vi]
+[vi.makepkg]
+linker = "mold"
+[vi.env]
+CXX = "clang++-18"
+FOO = "bar"
+"#,
+        )
+        .unwrap();
+        let resolved = Makepkg::resolve(&conf, conf.get("vi"), &conf.targets[0]).unwrap();
+        assert_eq!(
+            resolved.file.get("LDFLAGS").as_deref(),
+            Some("\"-Wl,-O1 -fuse-ld=mold\"")
+        );
+        assert!(resolved
+            .file
+            .get("RUSTFLAGS")
+            .is_some_and(|f| f.ends_with(" -Clink-arg=-fuse-ld=mold\"")));
+        assert_eq!(
+            resolved.env,
+            BTreeMap::from(
+                [("CC", "clang"), ("CXX", "clang++-18"), ("FOO", "bar")]
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+            )
+        );
+        assert_eq!(resolved.packages, ["clang", "mold"]);
+        assert_eq!(
+            resolved.origins["LDFLAGS"],
+            [Origin::Global, Origin::Package("vi".to_string())]
+        );
+        assert_eq!(resolved.origins["CXX"], [Origin::Env("vi".to_string())]);
+        assert!(matches!(
+            conf_from("[makepkg]\ntoolchain = \"icc\"\n"),
+            Err(ConfError::Format(_))
+        ));
+    }
+
+    #[test]
+    fn remove_package() {
+        let content = r#"server_dir = "/srv/pacage"
+vim = { deps = true }
+
+[vi]
+deps = true
+[vi.env]
+CC = "clang"
+
+# Comment kept
+[vim-plugins]
+[makepkg]
+cflags = "-O2"
+"#;
+        assert_eq!(remove_package_table(content, "unknown"), None);
+        assert_eq!(
+            remove_package_table(content, "vi").unwrap(),
+            r#"server_dir = "/srv/pacage"
+vim = { deps = true }
+
+# Comment kept
+[vim-plugins]
+[makepkg]
+cflags = "-O2"
+"#
+        );
+        let content = remove_package_table(content, "vim").unwrap();
+        assert!(!content.contains("vim ="));
+        assert!(content.contains("[vim-plugins]"));
+    }
+
+    #[test]
+    fn targets() {
+        let conf = conf_from("[vi]\n").unwrap();
+        assert_eq!(conf.targets, [Target::default()]);
+        let target = &conf.targets[0];
+        assert_eq!(
+            conf.get_repo_db(target),
+            conf.server_dir.join("repo").join("pacage.db")
+        );
+        assert_eq!(
+            conf.repo_archive(&conf.get_repo_db(target)),
+            conf.server_dir.join("repo").join("pacage.db.tar.gz")
+        );
+        let conf = conf_from("repo_name = \"custom\"\ndb_compression = \"zstd\"\n").unwrap();
+        let target = &conf.targets[0];
+        assert_eq!(target.name, "custom");
+        assert_eq!(
+            conf.repo_archive(&conf.get_repo_files_db(target)),
+            conf.server_dir.join("repo").join("custom.files.tar.zst")
+        );
+        assert!(conf_from("db_compression = \"lz4\"\n").is_err());
+
+        let conf = conf_from(
+            r#"
+[makepkg]
+cflags = "-O2"
+
+[profiles.v3]
+cflags = "-march=x86-64-v3 -O2"
+[profiles.lto]
+ltoflags = "-flto=auto"
+
+[targets.pacage-v3]
+profile = "v3"
+[targets.pacage]
+
+[vi]
+profile = "lto"
+"#,
+        )
+        .unwrap();
+        assert_eq!(conf.targets.len(), 2);
+        let v3 = conf.target(Some("pacage-v3")).unwrap();
+        let default = conf.target(Some("pacage")).unwrap();
+        assert_eq!(conf.target(None).unwrap(), default);
+        assert_eq!(
+            conf.get_repo_files_db(v3),
+            conf.server_dir
+                .join("repo")
+                .join("pacage-v3")
+                .join("pacage-v3.files")
+        );
+        assert_eq!(
+            conf.get_repo_files_db(default),
+            conf.server_dir.join("repo").join("pacage.files")
+        );
+        let resolved = Makepkg::resolve(&conf, conf.get("vi"), v3).unwrap();
+        assert_eq!(
+            resolved.file.get("CFLAGS").as_deref(),
+            Some("\"-march=x86-64-v3 -O2\"")
+        );
+        let profile = |name: &str| Origin::Profile(name.to_string());
+        assert_eq!(
+            conf.makepkg_layers(conf.get("vi"), v3)
+                .into_iter()
+                .map(|(origin, _)| origin)
+                .collect::<Vec<_>>(),
+            [Origin::Global, profile("v3"), profile("lto")]
+        );
+        let resolved = Makepkg::resolve(&conf, conf.get("vi"), default).unwrap();
+        assert_eq!(resolved.file.get("CFLAGS").as_deref(), Some("\"-O2\""));
+        assert!(conf.target(Some("missing")).is_err());
+        assert!(matches!(
+            conf_from("[targets.pacage]\nprofile = \"missing\"\n"),
+            Err(ConfError::Format(_))
+        ));
+        // No default target
+        for conf in ["targets = {}\n", "[targets.a]\n"] {
+            assert!(matches!(conf_from(conf), Err(ConfError::Format(_))));
+        }
+    }
 }
@@ -1,19 +1,23 @@
-use flate2::read::GzDecoder;
-use flate2::GzBuilder;
-use log::{error, warn};
+use log::{error, info, warn};
 use nix::NixPath;
-use ruzstd::StreamingDecoder;
+use rayon::prelude::*;
 use sha2::{Digest, Sha256};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use tar::Archive;
 use thiserror::Error;
 
-use crate::conf::Conf;
+use crate::compression::Compression;
+use crate::conf::{Conf, Target};
 
 use crate::format::{DbDesc, DbDescError, PkgInfo, SrcInfo};
+use crate::pkgrel;
+use crate::sign::{self, sig_path, SignError};
+use crate::use_flags::UseFlags;
 use crate::utils::file_lock::DirLock;
 use crate::utils::version::Version;
 
@@ -32,7 +36,7 @@ const TMP_FILES: &str = "pacage.files.tmp";
 ========
 ===== pacage.files.tar.gz =====
 ├ ${pkgname1}-${pkgver1}/    # One directory per package - version
-│ ├ files                    # List of all the files (sort -u) (except hidden) in pkgfile
+│ ├ files                    # List of all the entries (sort -u) (except hidden) in pkgfile
 │ └ desc                     # Same desc file as db (format::DbDesc)
 ├ ${pkgname2}-${pkgver2}/
 │ ├ files
@@ -69,47 +73,184 @@ pub enum RepoError {
     Parsing(#[from] DbDescError),
 }
 
+/// Inconsistency between the repo db and the package files, see check
+#[derive(Debug, PartialEq, Eq)]
+pub enum RepoIssue {
+    /// Package file of a db entry not found
+    Missing { name: String, file: String },
+    /// Package file not matching its db entry
+    Corrupted {
+        name: String,
+        file: String,
+        reason: String,
+    },
+    /// Package file not in the db
+    Orphan(String),
+    /// Several versions of a package in the db
+    Duplicate { name: String, versions: Vec<String> },
+}
+
+impl fmt::Display for RepoIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing { name, file } => write!(f, "[{}] Missing package file {}", name, file),
+            Self::Corrupted { name, file, reason } => write!(f, "[{}] {}: {}", name, file, reason),
+            Self::Orphan(file) => write!(f, "{} is not in the db", file),
+            Self::Duplicate { name, versions } => {
+                write!(f, "[{}] Duplicated versions: {}", name, versions.join(", "))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum AddError {
     #[error("Failed to parse all the packages")]
     Nothing,
-    #[error("Failed to lock database")]
-    DbLockError,
+    #[error("Failed to lock database: {0}")]
+    DbLockError(std::io::Error),
     #[error("System error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Parsing error: {0}")]
     Parsing(String),
     #[error("Encoding error: {0}")]
     Encoding(String),
+    #[error("Signing error: {0}")]
+    Sign(#[from] SignError),
+    #[error("No archived package: {0}")]
+    NotArchived(String),
+    #[error("[{name}] A newer version({current}) is already present in database, trying to add {new}, use --allow-downgrade to replace it")]
+    Downgrade {
+        name: String,
+        current: String,
+        new: String,
+    },
 }
 
-pub fn list(conf: &Conf) -> Result<Vec<DbDesc>, RepoError> {
-    let mut pkgs = Vec::new();
-    let tar_gz = File::open(conf.get_repo_db()).map_err(|_| RepoError::NoRepo)?;
-    let tar = GzDecoder::new(tar_gz);
-    let mut archive = Archive::new(tar);
-    // TODO: check for duplicated pkgs (same pkg not the same version)
+#[derive(Debug, Error)]
+pub enum RemoveError {
+    #[error("Not in the repo: {0}")]
+    NotFound(String),
+    #[error("Failed to lock database: {0}")]
+    DbLockError(std::io::Error),
+    #[error("Repo error: {0}")]
+    Repo(#[from] RepoError),
+    #[error("System error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Signing error: {0}")]
+    Sign(#[from] SignError),
+}
+
+// Host pacman sync databases, to compare our builds with the official packages
+pub const SYNC_DB_DIR: &str = "/var/lib/pacman/sync";
+
+pub fn list(conf: &Conf, target: &Target) -> Result<Vec<DbDesc>, RepoError> {
+    list_db(&readable(conf, &conf.get_repo_db(target)))
+}
+
+/// Call f on every file of every package of the repo files database
+pub fn read_repo_files(conf: &Conf, target: &Target, f: impl FnMut(&str)) -> Result<(), RepoError> {
+    read_files_db(&readable(conf, &conf.get_repo_files_db(target)), f)
+}
+
+// The <repo>.db link, the db_compression archive for the repos written before the links
+fn readable(conf: &Conf, link: &Path) -> PathBuf {
+    if link.exists() {
+        link.to_path_buf()
+    } else {
+        conf.repo_archive(link)
+    }
+}
+
+// Any compression
+fn open_db(path: &Path) -> Result<Archive<Box<dyn Read>>, RepoError> {
+    match Compression::open(path) {
+        Ok(reader) => Ok(Archive::new(reader)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(RepoError::NoRepo),
+        Err(e) => Err(e)?,
+    }
+}
+
+// Host sync databases (<repo><extension>) by repo name, the pacage repos are skipped
+fn sync_dbs(conf: &Conf, extension: &str) -> Result<Vec<(String, PathBuf)>, io::Error> {
+    let mut res = Vec::new();
+    for entry in fs::read_dir(SYNC_DB_DIR)? {
+        let path = entry?.path();
+        let Some(repo) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(extension))
+        else {
+            continue;
+        };
+        if !conf.targets.iter().any(|target| target.name == repo) {
+            res.push((repo.to_string(), path.clone()));
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+/// Packages of the host sync databases by repo, the pacage repos are skipped
+pub fn sync_list(conf: &Conf) -> Result<Vec<(String, Vec<DbDesc>)>, RepoError> {
+    let mut res = Vec::new();
+    for (repo, path) in sync_dbs(conf, ".db")? {
+        match list_db(&path) {
+            Ok(pkgs) => res.push((repo, pkgs)),
+            Err(e) => warn!("Failed to read the {} sync database: {}", repo, e),
+        }
+    }
+    Ok(res)
+}
+
+/// Host sync files databases (pacman -Fy), the pacage repos are skipped
+pub fn sync_files_dbs(conf: &Conf) -> Result<Vec<(String, PathBuf)>, io::Error> {
+    sync_dbs(conf, ".files")
+}
+
+/// Call f on every file of every package of a files database
+pub fn read_files_db(path: &Path, mut f: impl FnMut(&str)) -> Result<(), RepoError> {
+    let mut archive = open_db(path)?;
     for entry in archive.entries()? {
-        if let Ok(entry) = entry {
-            if let Ok(path) = entry.path() {
-                if path
-                    .file_name()
-                    .is_some_and(|name| name.to_str() == Some("desc"))
-                {
-                    DbDesc::new(BufReader::new(entry)).map(|p| pkgs.push(p))?;
-                }
+        let entry = entry?;
+        if entry.path()?.file_name().and_then(|name| name.to_str()) != Some("files") {
+            continue;
+        }
+        for line in BufReader::new(entry).lines() {
+            let line = line?;
+            if !line.is_empty() && line != "%FILES%" {
+                f(&line);
             }
         }
     }
+    Ok(())
+}
+
+fn list_db(path: &Path) -> Result<Vec<DbDesc>, RepoError> {
+    let mut pkgs = Vec::new();
+    let mut archive = open_db(path)?;
+    // The duplicated pkgs (same pkg not the same version) are reported by check
+    for entry in archive.entries()? {
+        // A corrupted db is an error, not an empty one
+        let entry = entry?;
+        if entry
+            .path()?
+            .file_name()
+            .is_some_and(|name| name.to_str() == Some("desc"))
+        {
+            DbDesc::new(BufReader::new(entry)).map(|p| pkgs.push(p))?;
+        }
+    }
     Ok(pkgs)
 }
 
-/// Generate the content of the "files" file
+/// Generate the content of the "files" file, same as repo-add:
+/// echo "%FILES%"; bsdtar --exclude='^.*' -tf "$pkgfile" | LC_ALL=C sort -u
 fn generate_files_file(files: BTreeSet<String>) -> Vec<u8> {
     let mut res = Vec::from(b"%FILES%\n");
     for file in files {
-        res.push(b'\n');
         res.extend_from_slice(file.as_bytes());
+        res.push(b'\n');
     }
     res
 }
@@ -125,11 +266,16 @@ fn read_package(
     ),
     AddError,
 > {
-    let mut tar_zst = File::open(pkgfile)
+    let pkg_file = File::open(pkgfile)
         .inspect_err(|e| error!("Failed to open the archive({}): {}", pkgfile, e))?;
+    let compression = Compression::from_file_name(pkgfile)
+        .ok_or_else(|| AddError::Encoding(format!("Unknown package compression: {}", pkgfile)))?;
+    // Single pass over the file: the raw bytes are hashed while decompressed
+    let mut raw = HashReader::new(BufReader::new(pkg_file));
     let mut archive = Archive::new(
-        StreamingDecoder::new(&tar_zst)
-            .map_err(|e| AddError::Encoding(format!("Zstd error: {}", e)))?,
+        compression
+            .decoder(&mut raw)
+            .map_err(|e| AddError::Encoding(e.to_string()))?,
     );
     let entries = archive
         .entries()
@@ -154,8 +300,13 @@ fn read_package(
                 PkgInfo::new(BufReader::new(entry))
                     .map_err(|e| AddError::Parsing(format!("Fail to parse .PKGINFO: {}", e)))?,
             );
-        } else if !path.starts_with(".") && entry.header().entry_type() == tar::EntryType::Regular {
-            files.insert(path.to_string());
+        } else if !path.starts_with(".") {
+            // Every entry, directories are listed with a trailing '/'
+            if entry.header().entry_type().is_dir() && !path.ends_with('/') {
+                files.insert(format!("{}/", path));
+            } else {
+                files.insert(path.to_string());
+            }
         }
     }
     let Some(pkginfo) = pkginfo else {
@@ -164,13 +315,49 @@ fn read_package(
         ))?
     };
 
-    let mut hasher = Sha256::new();
-    let csize = io::copy(&mut tar_zst, &mut hasher)
+    drop(archive);
+
+    // The decoder stops at the end of the tar, hash the trailing bytes
+    io::copy(&mut raw, &mut io::sink())
         .inspect_err(|e| error!("Failed to read pkg archive to get the hash: {}", e))?;
-    let sha256 = base16ct::lower::encode_string(&hasher.finalize());
+    let (csize, sha256) = raw.finish();
     Ok((pkginfo, csize, sha256, files))
 }
 
+/// Reader hashing (sha256) and counting the bytes read through it
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// (size, sha256) of everything read
+    fn finish(self) -> (u64, String) {
+        (
+            self.size,
+            base16ct::lower::encode_string(&self.hasher.finalize()),
+        )
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
 fn parse_path_name(path: &Path) -> Result<(String /* pkgname */, Version), String> {
     let path = path.to_string_lossy();
     let second = path
@@ -194,14 +381,16 @@ fn parse_path_name(path: &Path) -> Result<(String /* pkgname */, Version), Strin
 fn copy_old_db<T>(
     out_tar: &mut tar::Builder<T>,
     repo_path: &Path,
+    allow_downgrade: bool,
     adding: &[AddEntry],
-    to_remove: &mut Vec<String>,
+    to_remove: &mut Vec<DbDesc>,
 ) -> Result<(), AddError>
 where
     T: Write,
 {
-    let repo = File::open(&repo_path).inspect_err(|e| error!("Failed to open the db: {}", e))?;
-    let mut archive = Archive::new(GzDecoder::new(&repo));
+    let mut archive = Archive::new(
+        Compression::open(repo_path).inspect_err(|e| error!("Failed to open the db: {}", e))?,
+    );
     let dbentries = archive
         .entries()
         .inspect_err(|e| error!("failed to read db: {}", e))?;
@@ -233,13 +422,19 @@ where
                         .find(|(_, pkginfo, _, _, _)| pkginfo.pkgname == ename)
                     {
                         Some((_, pkginfo, _, _, _)) => {
-                            if eversion > pkginfo.version {
-                                // warning "$(gettext "A newer version for '%s' is already present in database")" "$pkgname"
-                                // if (( PREVENT_DOWNGRADE )); then
-                                // 	return 0
-                                warn!("[{}] A newer version({}) is already present in database, trying to install: {}", ename, eversion, pkginfo.version);
-                                unimplemented!();
-                            } else if eversion < pkginfo.version {
+                            if eversion > pkginfo.version && !allow_downgrade {
+                                return Err(AddError::Downgrade {
+                                    name: ename,
+                                    current: eversion.to_string(),
+                                    new: pkginfo.version.to_string(),
+                                });
+                            } else if eversion != pkginfo.version {
+                                if eversion > pkginfo.version {
+                                    warn!(
+                                        "[{}] Downgrading from {} to {}",
+                                        ename, eversion, pkginfo.version
+                                    );
+                                }
                                 let edesc =
                                     DbDesc::new(BufReader::new(&mut entry)).map_err(|e| {
                                         AddError::Parsing(format!(
@@ -247,7 +442,7 @@ where
                                             e
                                         ))
                                     })?;
-                                to_remove.push(edesc.filename);
+                                to_remove.push(edesc);
                             }
                         }
                         None => {
@@ -278,9 +473,10 @@ fn copy_old_files<T>(
 where
     T: Write,
 {
-    let repo =
-        File::open(&files_path).inspect_err(|e| error!("Failed to open the files db: {}", e))?;
-    let mut archive = Archive::new(GzDecoder::new(&repo));
+    let mut archive = Archive::new(
+        Compression::open(files_path)
+            .inspect_err(|e| error!("Failed to open the files db: {}", e))?,
+    );
     let entries = archive
         .entries()
         .inspect_err(|e| error!("failed to read the files db: {}", e))?;
@@ -333,145 +529,725 @@ type AddEntry = (
     BTreeSet<String>,
 );
 
-/// Basicly repo-add reimplementation
-pub fn add(conf: &Conf, pkgs: &[SrcInfo]) -> Result<(), AddError> {
-    let mut pkgfiles: Vec<AddEntry> = Vec::new();
-    let mut to_remove = vec![];
+/// File name of the built package without PKGEXT
+pub fn pkgfile_stem(pkg: &SrcInfo) -> String {
+    pkgname_stem(pkg, &pkg.name)
+}
 
-    for pkg in pkgs {
-        let pkgfile = conf
-            .server_dir
-            .join("repo")
-            .join(format!(
-                "{}-{}-{}.pkg.tar.zst",
-                pkg.name,
-                pkg.get_version(),
-                pkg.arch
-            ))
-            .to_string_lossy()
-            .to_string();
-        let (pkginfo, csize, sha256, files) = match read_package(&pkgfile) {
-            Ok(v) => v,
-            Err(e) => {
-                error!("[{}({})] {}", pkg.name, pkg.get_version(), e);
-                continue;
+fn pkgname_stem(pkg: &SrcInfo, pkgname: &str) -> String {
+    format!("{}-{}-{}", pkgname, pkg.get_version(), pkg.arch)
+}
+
+// Every package of a split package, the pkgbase alone otherwise
+fn pkgfile_stems(pkg: &SrcInfo) -> Vec<String> {
+    if pkg.pkgnames.is_empty() {
+        return vec![pkgfile_stem(pkg)];
+    }
+    pkg.pkgnames
+        .iter()
+        .map(|pkgname| pkgname_stem(pkg, pkgname))
+        .collect()
+}
+
+/// Whether files contain every built package, with any compression
+pub fn has_pkgfiles(files: &[String], pkg: &SrcInfo) -> bool {
+    pkgfile_stems(pkg).iter().all(|stem| {
+        files.iter().any(|file| {
+            file.strip_prefix(stem)
+                .is_some_and(|ext| Compression::from_extension(ext).is_some())
+        })
+    })
+}
+
+/// File name of the built package in dir, the latest one if it was built with several PKGEXT
+pub fn find_pkgfile(dir: &Path, pkg: &SrcInfo) -> Option<String> {
+    find_stem(dir, &pkgfile_stem(pkg))
+}
+
+/// File names of every built package in dir, None if one is missing
+pub fn find_pkgfiles(dir: &Path, pkg: &SrcInfo) -> Option<Vec<String>> {
+    pkgfile_stems(pkg)
+        .iter()
+        .map(|stem| find_stem(dir, stem))
+        .collect()
+}
+
+fn find_stem(dir: &Path, stem: &str) -> Option<String> {
+    Compression::ALL
+        .iter()
+        .map(|compression| format!("{}{}", stem, compression.extension()))
+        .filter_map(|file| {
+            let modified = fs::metadata(dir.join(&file)).ok()?.modified().ok()?;
+            Some((modified, file))
+        })
+        .max()
+        .map(|(_, file)| file)
+}
+
+/// Move the new db signature next to the db, pacman looks for <repo>.db.sig (link).
+/// Without signature the old one is removed, it would not match the new db.
+fn update_db_sig(new_sig: Option<PathBuf>, db: &Path, link: &Path) -> Result<(), io::Error> {
+    let sig = sig_path(db);
+    fs::remove_file(link).or_else(|e| match e.kind() {
+        ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+    match new_sig {
+        Some(new_sig) => {
+            fs::rename(new_sig, &sig)?;
+            if let Some(name) = sig.file_name() {
+                symlink(name, link)?;
             }
-        };
-        if &pkginfo.version != pkg.get_version() {
-            error!(
-                "[{}] Version mismatch from created package({}) to request package({})",
-                pkg.name,
-                pkginfo.version,
-                pkg.get_version()
-            );
+        }
+        None => fs::remove_file(sig).or_else(|e| match e.kind() {
+            ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })?,
+    }
+    Ok(())
+}
+
+// Point the <repo>.db link to the new archive, the archive of another db_compression is removed
+fn update_db_link(link: &Path, archive: &Path) -> Result<(), io::Error> {
+    let (Some(dir), Some(name)) = (link.parent(), archive.file_name()) else {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Invalid db path"));
+    };
+    let old = fs::read_link(link).ok();
+    let mut tmp = link.as_os_str().to_owned();
+    tmp.push(".link.tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::remove_file(&tmp).ok();
+    symlink(name, &tmp)?;
+    fs::rename(tmp, link)?;
+    if let Some(old) = old.filter(|old| old.as_os_str() != name) {
+        let old = dir.join(old);
+        fs::remove_file(&old)?;
+        fs::remove_file(sig_path(&old)).ok();
+    }
+    Ok(())
+}
+
+type DbBuilder = tar::Builder<Vec<u8>>;
+
+// 2 new dbs (db, files), compressed by write_dbs
+fn new_dbs() -> (DbBuilder, DbBuilder) {
+    (tar::Builder::new(Vec::new()), tar::Builder::new(Vec::new()))
+}
+
+// Write, sign and atomically replace both dbs
+fn write_dbs<E: From<io::Error> + From<SignError>>(
+    conf: &Conf,
+    target: &Target,
+    repo_lock: &DirLock,
+    tar_new_db: DbBuilder,
+    tar_new_files: DbBuilder,
+) -> Result<(), E> {
+    for (tar, tmp_name, link) in [
+        (tar_new_db, TMP_DB, conf.get_repo_db(target)),
+        (tar_new_files, TMP_FILES, conf.get_repo_files_db(target)),
+    ] {
+        let tmp = repo_lock.path().join(tmp_name);
+        let archive = conf.repo_archive(&link);
+        let name = archive.file_name().unwrap_or_default().to_string_lossy();
+
+        // Write to disc
+        let data = tar
+            .into_inner()
+            .inspect_err(|e| error!("Failed to write out {} archive: {}", name, e))?;
+        let mut out = File::create(&tmp)
+            .inspect_err(|e| error!("Failed to create tmp out {}: {}", name, e))?;
+        conf.db_compression
+            .compress(&data, &mut out)
+            .inspect_err(|e| error!("Failed to compress out {}: {}", name, e))?;
+        out.sync_all()
+            .inspect_err(|e| error!("Failed to sync out {}: {}", name, e))?;
+        drop(out);
+
+        let sig =
+            sign::sign(conf, &tmp).inspect_err(|e| error!("Failed to sign {}: {}", name, e))?;
+
+        // Atomic update
+        fs::rename(&tmp, &archive)
+            .inspect_err(|e| error!("Failed to overwrite old {} with new one: {}", name, e))?;
+        update_db_link(&link, &archive)
+            .inspect_err(|e| error!("Failed to update the {} link: {}", name, e))?;
+        update_db_sig(sig, &archive, &sig_path(&link))
+            .inspect_err(|e| error!("Failed to update the {} signature: {}", name, e))?;
+    }
+    Ok(())
+}
+
+// Remove a package file, its signature and its archived fingerprint
+fn remove_pkgfile(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!(
+            "Failed to remove old package file({}): {}",
+            path.display(),
+            e
+        );
+    }
+    for sidecar in [sig_path(path), fingerprint_path(path)] {
+        match fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                error!("Failed to remove {}: {}", sidecar.display(), e)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Fingerprint of an archived package file, its db entry is gone
+fn fingerprint_path(path: &Path) -> PathBuf {
+    let mut fingerprint = path.as_os_str().to_owned();
+    fingerprint.push(".fingerprint");
+    PathBuf::from(fingerprint)
+}
+
+// Repo dbs lock, the new dbs are written in it
+fn lock_repo(conf: &Conf, target: &Target) -> Result<DirLock, io::Error> {
+    DirLock::new(
+        conf.get_repo_db(target).with_extension("lock"),
+        conf.wait_lock,
+    )
+}
+
+// Move a package file and its signature to another dir
+fn move_pkgfile(from: &Path, to: &Path, file: &str) -> Result<(), io::Error> {
+    fs::create_dir_all(to)?;
+    fs::rename(from.join(file), to.join(file))?;
+    match fs::rename(sig_path(&from.join(file)), sig_path(&to.join(file))) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// name-1.0-1-x86_64.pkg.tar.zst -> (name, 1.0-1)
+fn parse_pkgfile_name(file: &str) -> Option<(String, Version)> {
+    let (rest, _) = file.rsplit_once(".pkg.tar")?;
+    let (rest, _arch) = rest.rsplit_once('-')?;
+    parse_path_name(Path::new(rest)).ok()
+}
+
+/// Package files (pkgname, version, file) of dir, newest first
+fn pkgfiles_in(dir: &Path) -> Result<Vec<(String, Version, String)>, io::Error> {
+    let mut res = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(res),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let file = entry?.file_name().to_string_lossy().to_string();
+        if Compression::from_file_name(&file).is_none() {
             continue;
         }
-        pkgfiles.push((pkgfile, pkginfo, csize, sha256, files));
+        if let Some((name, version)) = parse_pkgfile_name(&file) {
+            res.push((name, version, file));
+        }
     }
+    res.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)));
+    Ok(res)
+}
+
+/// Archived package files (pkgname, version, file) of the target, newest first
+pub fn archived(conf: &Conf, target: &Target) -> Result<Vec<(String, Version, String)>, io::Error> {
+    pkgfiles_in(&conf.archive_dir(target))
+}
+
+// Package file of a replaced db entry, archived with its fingerprint when keep_versions is set
+fn retire_pkgfile(conf: &Conf, target: &Target, desc: &DbDesc) {
+    let repo_dir = conf.repo_dir(target);
+    let file = desc.filename.as_str();
+    if conf.keep_versions == 0 {
+        return remove_pkgfile(&repo_dir.join(file));
+    }
+    let archive_dir = conf.archive_dir(target);
+    if let Err(e) = move_pkgfile(&repo_dir, &archive_dir, file) {
+        error!("Failed to archive old package file({}): {}", file, e);
+        return remove_pkgfile(&repo_dir.join(file));
+    }
+    if let Some(fingerprint) = &desc.fingerprint {
+        if let Err(e) = fs::write(fingerprint_path(&archive_dir.join(file)), fingerprint) {
+            error!("[{}] Failed to archive the fingerprint: {}", desc.name, e);
+        }
+    }
+    // Only the last keep_versions of each package
+    let archived = match archived(conf, target) {
+        Ok(archived) => archived,
+        Err(e) => return error!("Failed to list the archived packages: {}", e),
+    };
+    let mut kept = 0;
+    for (i, (name, _, file)) in archived.iter().enumerate() {
+        kept = if i > 0 && archived[i - 1].0 == *name {
+            kept + 1
+        } else {
+            1
+        };
+        if kept > conf.keep_versions {
+            remove_pkgfile(&archive_dir.join(file));
+        }
+    }
+}
+
+/// Basicly repo-add reimplementation
+pub fn add(conf: &Conf, target: &Target, pkgs: &[SrcInfo]) -> Result<(), AddError> {
+    let repo_dir = conf.repo_dir(target);
+    // Big packages, read (decompress + hash) them in parallel
+    let pkgfiles: Vec<AddEntry> = pkgs
+        .par_iter()
+        .filter_map(|pkg| {
+            let Some(pkgfile) = find_pkgfile(&repo_dir, pkg) else {
+                error!(
+                    "[{}({})] Missing package file {}{{{}}}",
+                    pkg.name,
+                    pkg.get_version(),
+                    pkgfile_stem(pkg),
+                    Compression::ALL.map(|c| c.extension()).join(",")
+                );
+                return None;
+            };
+            let pkgfile = repo_dir.join(pkgfile).to_string_lossy().to_string();
+            let (pkginfo, csize, sha256, files) = match read_package(&pkgfile) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("[{}({})] {}", pkg.name, pkg.get_version(), e);
+                    return None;
+                }
+            };
+            if &pkginfo.version != pkg.get_version() {
+                error!(
+                    "[{}] Version mismatch from created package({}) to request package({})",
+                    pkg.name,
+                    pkginfo.version,
+                    pkg.get_version()
+                );
+                return None;
+            }
+            if let Err(e) = sign::sign(conf, Path::new(&pkgfile)) {
+                error!("[{}] Failed to sign the package: {}", pkg.name, e);
+                return None;
+            }
+            Some((pkgfile, pkginfo, csize, sha256, files))
+        })
+        .collect();
     if pkgfiles.len() == 0 {
         return Err(AddError::Nothing);
     }
+    let repo_lock = lock_repo(conf, target).map_err(AddError::DbLockError)?;
+    // The built ones have the inputs fingerprint
+    add_entries(
+        conf,
+        target,
+        &repo_lock,
+        pkgfiles,
+        conf.allow_downgrade,
+        |desc| {
+            let base = desc.base.as_ref().unwrap_or(&desc.name);
+            desc.fingerprint = pkgs
+                .iter()
+                .find(|pkg| pkg.name == *base)
+                .and_then(|pkg| pkg.fingerprint.clone());
+        },
+    )
+}
 
-    let repo_lock = DirLock::new(conf.get_repo_db().with_extension("lock")).map_err(|e| {
-        error!("Failed to lock db: {}", e);
-        AddError::DbLockError
-    })?;
+// Write the read package archives in the dbs (locked by repo_lock), the replaced ones are
+// archived. extend sets the pacage fields of the new entries.
+fn add_entries(
+    conf: &Conf,
+    target: &Target,
+    repo_lock: &DirLock,
+    pkgfiles: Vec<AddEntry>,
+    allow_downgrade: bool,
+    extend: impl Fn(&mut DbDesc),
+) -> Result<(), AddError> {
+    let mut to_remove = vec![];
 
-    // Create 2 new temporary dbs
-    let tar_new_db_path = repo_lock.path().join(TMP_DB);
-    let tar_new_files_path = repo_lock.path().join(TMP_FILES);
-    let mut tar_new_db = tar::Builder::new(
-        GzBuilder::new().write(
-            File::create(&tar_new_db_path)
-                .inspect_err(|e| error!("Failed to create tmp out db: {}", e))?,
-            flate2::Compression::default(),
-        ),
-    );
-    let mut tar_new_files = tar::Builder::new(
-        GzBuilder::new().write(
-            File::create(&tar_new_files_path)
-                .inspect_err(|e| error!("Failed to create tmp out files: {}", e))?,
-            flate2::Compression::default(),
-        ),
-    );
+    let (mut tar_new_db, mut tar_new_files) = new_dbs();
 
     // Copy old relevant(everything except our package) entries into the new db
-    let repo_path = conf.get_repo_db();
-    let files_path = conf.get_repo_files_db();
+    let repo_path = readable(conf, &conf.get_repo_db(target));
+    let files_path = readable(conf, &conf.get_repo_files_db(target));
     if repo_path.exists() {
-        copy_old_db(&mut tar_new_db, &repo_path, &pkgfiles, &mut to_remove)?;
+        copy_old_db(
+            &mut tar_new_db,
+            &repo_path,
+            allow_downgrade,
+            &pkgfiles,
+            &mut to_remove,
+        )?;
     }
     if files_path.exists() {
         copy_old_files(&mut tar_new_files, &files_path, &pkgfiles)?;
     }
 
-    for (pkgfile, pkginfo, csize, sha256, files) in pkgfiles {
-        // Write desc in both db and files
-        let pgpsig = if PathBuf::from(format!("{}.sig", pkgfile)).exists() {
-            // compute base64'd PGP signature
-            unimplemented!()
-        } else {
-            None
-        };
-        let version = pkginfo.version.to_string();
-        let desc_path = format!("{}-{}/desc", pkginfo.pkgname, &version);
-        let desc = pkginfo.to_desc(pkgfile, csize, sha256, pgpsig);
-        let mut desc_raw = vec![];
-        desc.write(&mut desc_raw)
-            .inspect_err(|e| error!("Fail to create new desc file: {}", e))?;
-        let mut desc_header = tar::Header::new_gnu();
-        desc_header.set_size(desc_raw.len() as u64);
-        tar_new_db
-            .append_data(&mut desc_header, &desc_path, desc_raw.as_slice())
-            .inspect_err(|e| error!("failed to copy db entry to output db: {}", e))?;
-        tar_new_files
-            .append_data(&mut desc_header, desc_path, desc_raw.as_slice())
-            .inspect_err(|e| error!("failed to copy db entry to output files: {}", e))?;
-
-        // Write files in files
-        let new_files_path = format!("{}-{}/files", pkginfo.pkgname, version);
-        let files_content = generate_files_file(files);
-        let mut files_header = tar::Header::new_gnu();
-        files_header.set_size(files_content.len() as u64);
-        tar_new_files
-            .append_data(&mut files_header, &new_files_path, files_content.as_slice())
-            .inspect_err(|e| error!("failed to copy db entry to output files: {}", e))?;
-    }
-    // Write both to disc
-    let db_out = tar_new_db
-        .into_inner()
-        .inspect_err(|e| error!("Failed to write out db archive: {}", e))?
-        .finish()
-        .inspect_err(|e| error!("Failed to write out db gz: {}", e))?;
-    db_out.sync_all().inspect_err(|e| {
-        error!("Failed to sync out db gz: {}", e);
-    })?;
-    drop(db_out);
-    let files_out = tar_new_files
-        .into_inner()
-        .inspect_err(|e| error!("Failed to write out files archive: {}", e))?
-        .finish()
-        .inspect_err(|e| error!("Failed to write out files gz: {}", e))?;
-    files_out
-        .sync_all()
-        .inspect_err(|e| error!("Failed to sync out files gz: {}", e))?;
-    drop(files_out);
-
-    // Atomic update of both dbs
-    fs::rename(tar_new_db_path, repo_path)
-        .inspect_err(|e| error!("Failed to overwrite old db with new one: {}", e))?;
-    fs::rename(tar_new_files_path, files_path)
-        .inspect_err(|e| error!("Failed to overwrite old files with new one: {}", e))?;
-
-    // Remove old pkg archives not present in the db any more
-    let a = conf.server_dir.join("repo");
-    for file in to_remove {
-        if let Err(e) = fs::remove_file(a.join(&file)) {
-            error!("Failed to remove old package file({}): {}", file, e);
+    for entry in pkgfiles {
+        append_entry(conf, &mut tar_new_db, &mut tar_new_files, entry, &extend)?;
+    }
+    write_dbs::<AddError>(conf, target, repo_lock, tar_new_db, tar_new_files)?;
+
+    // Archive or remove old pkg archives not present in the db any more
+    for desc in to_remove {
+        retire_pkgfile(conf, target, &desc);
+    }
+    Ok(())
+}
+
+// Write the desc and files entries of a read package in the dbs.
+// extend sets the pacage fields (fingerprint, hold) of the desc.
+fn append_entry(
+    conf: &Conf,
+    tar_new_db: &mut DbBuilder,
+    tar_new_files: &mut DbBuilder,
+    (pkgfile, pkginfo, csize, sha256, files): AddEntry,
+    extend: impl FnOnce(&mut DbDesc),
+) -> Result<(), AddError> {
+    // Write desc in both db and files
+    let sig = sig_path(Path::new(&pkgfile));
+    let pgpsig = if sig.exists() {
+        Some(sign::pgpsig(&sig)?)
+    } else {
+        None
+    };
+    let version = pkginfo.version.to_string();
+    let desc_path = format!("{}-{}/desc", pkginfo.pkgname, &version);
+    // %FILENAME% is relative to the repo dir
+    let filename = Path::new(&pkgfile)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(pkgfile);
+    let mut desc = pkginfo.to_desc(filename, csize, sha256, pgpsig);
+    let base = desc.base.clone().unwrap_or_else(|| desc.name.clone());
+    match UseFlags::new(conf, &base) {
+        Ok(flags) => desc.use_flags = flags.list(),
+        Err(e) => error!("[{}] Failed to get USE flags: {}", base, e),
+    }
+    extend(&mut desc);
+    let mut desc_raw = vec![];
+    desc.write(&mut desc_raw)
+        .inspect_err(|e| error!("Fail to create new desc file: {}", e))?;
+    let mut desc_header = tar::Header::new_gnu();
+    desc_header.set_size(desc_raw.len() as u64);
+    tar_new_db
+        .append_data(&mut desc_header, &desc_path, desc_raw.as_slice())
+        .inspect_err(|e| error!("failed to copy db entry to output db: {}", e))?;
+    tar_new_files
+        .append_data(&mut desc_header, desc_path, desc_raw.as_slice())
+        .inspect_err(|e| error!("failed to copy db entry to output files: {}", e))?;
+
+    // Write files in files
+    let new_files_path = format!("{}-{}/files", pkginfo.pkgname, version);
+    let files_content = generate_files_file(files);
+    let mut files_header = tar::Header::new_gnu();
+    files_header.set_size(files_content.len() as u64);
+    tar_new_files
+        .append_data(&mut files_header, &new_files_path, files_content.as_slice())
+        .inspect_err(|e| error!("failed to copy db entry to output files: {}", e))?;
+    Ok(())
+}
+
+/// Verify the db entries against their package files (size and sha256), report the missing,
+/// orphan and duplicated packages.
+pub fn check(conf: &Conf, target: &Target) -> Result<Vec<RepoIssue>, RepoError> {
+    let repo_dir = conf.repo_dir(target);
+    let descs = list(conf, target)?;
+    let mut issues = Vec::new();
+
+    let mut versions = BTreeMap::<&str, Vec<String>>::new();
+    for desc in &descs {
+        versions
+            .entry(&desc.name)
+            .or_default()
+            .push(desc.version.clone());
+    }
+    for (name, versions) in versions {
+        if versions.len() > 1 {
+            issues.push(RepoIssue::Duplicate {
+                name: name.to_string(),
+                versions,
+            });
+        }
+    }
+
+    let checked: Vec<Option<RepoIssue>> = descs
+        .par_iter()
+        .map(|desc| {
+            let name = desc.name.clone();
+            let file = desc.filename.clone();
+            let reason = match hash_file(&repo_dir.join(&desc.filename)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Some(RepoIssue::Missing { name, file })
+                }
+                Err(e) => e.to_string(),
+                Ok((csize, _)) if csize != desc.csize => {
+                    format!("Size {} instead of {}", csize, desc.csize)
+                }
+                Ok((_, sha256)) if sha256 != desc.shasum => {
+                    format!("sha256 {} instead of {}", sha256, desc.shasum)
+                }
+                Ok(_) => return None,
+            };
+            Some(RepoIssue::Corrupted { name, file, reason })
+        })
+        .collect();
+    issues.extend(checked.into_iter().flatten());
+
+    let listed: BTreeSet<&str> = descs.iter().map(|desc| desc.filename.as_str()).collect();
+    for (_, _, file) in pkgfiles_in(&repo_dir)? {
+        if !listed.contains(file.as_str()) {
+            issues.push(RepoIssue::Orphan(file));
+        }
+    }
+    Ok(issues)
+}
+
+/// (size, sha256) of a file
+pub(crate) fn hash_file(path: &Path) -> Result<(u64, String), io::Error> {
+    let mut raw = HashReader::new(BufReader::new(File::open(path)?));
+    io::copy(&mut raw, &mut io::sink())?;
+    Ok(raw.finish())
+}
+
+/// Regenerate both dbs from the package files of the repo dir, only the newest version of a
+/// package is added. The fingerprints and holds of the old db entries are kept when it is readable.
+/// The added package files are returned.
+pub fn rebuild(conf: &Conf, target: &Target) -> Result<Vec<String>, AddError> {
+    let repo_dir = conf.repo_dir(target);
+    let repo_lock = lock_repo(conf, target).map_err(AddError::DbLockError)?;
+    let old = match list(conf, target) {
+        Ok(old) => old,
+        Err(RepoError::NoRepo) => Vec::new(),
+        Err(e) => {
+            warn!("Unreadable db, the build fingerprints are lost: {}", e);
+            Vec::new()
+        }
+    };
+
+    let mut newest: Vec<(String, Version, String)> = Vec::new();
+    for (name, version, file) in pkgfiles_in(&repo_dir)? {
+        match newest.last() {
+            Some((last, last_version, _)) if *last == name => {
+                warn!("[{}] {} skipped, {} is newer", name, file, last_version)
+            }
+            _ => newest.push((name, version, file)),
+        }
+    }
+    let pkgfiles: Vec<AddEntry> = newest
+        .par_iter()
+        .filter_map(|(name, _, file)| {
+            let pkgfile = repo_dir.join(file).to_string_lossy().to_string();
+            match read_package(&pkgfile) {
+                Ok((pkginfo, csize, sha256, files)) => {
+                    Some((pkgfile, pkginfo, csize, sha256, files))
+                }
+                Err(e) => {
+                    error!("[{}] Failed to read {}: {}", name, file, e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let (mut tar_new_db, mut tar_new_files) = new_dbs();
+    let mut added = Vec::new();
+    for entry in pkgfiles {
+        if let Some(file) = Path::new(&entry.0).file_name() {
+            added.push(file.to_string_lossy().to_string());
         }
+        append_entry(conf, &mut tar_new_db, &mut tar_new_files, entry, |desc| {
+            if let Some(old) = old.iter().find(|old| old.shasum == desc.shasum) {
+                desc.fingerprint = old.fingerprint.clone();
+                desc.hold = old.hold;
+            }
+        })?;
     }
+    write_dbs::<AddError>(conf, target, &repo_lock, tar_new_db, tar_new_files)?;
     drop(repo_lock);
-    Ok(())
+    Ok(added)
+}
+
+/// Basicly repo-remove reimplementation, names are pkgnames or pkgbases.
+/// The package files are deleted, the removed entries are returned.
+pub fn remove(conf: &Conf, target: &Target, names: &[String]) -> Result<Vec<DbDesc>, RemoveError> {
+    let repo_path = readable(conf, &conf.get_repo_db(target));
+    let files_path = readable(conf, &conf.get_repo_files_db(target));
+    if !repo_path.exists() {
+        Err(RepoError::NoRepo)?
+    }
+    let repo_lock = lock_repo(conf, target).map_err(RemoveError::DbLockError)?;
+    let (mut tar_new_db, mut tar_new_files) = new_dbs();
+
+    // Copy the db without the removed entries, "<pkgname>-<version>" dirs
+    let mut removed = Vec::new();
+    let mut removed_dirs = BTreeSet::new();
+    let mut entries = Vec::new();
+    let mut archive = open_db(&repo_path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let mut raw = Vec::new();
+        io::copy(&mut entry, &mut raw)?;
+        if path.file_name().and_then(|name| name.to_str()) == Some("desc") {
+            let desc = DbDesc::new(raw.as_slice()).map_err(RepoError::from)?;
+            if names.contains(&desc.name) || desc.base.as_ref().is_some_and(|b| names.contains(b)) {
+                removed_dirs.insert(path.parent().map(Path::to_path_buf).unwrap_or_default());
+                removed.push(desc);
+            }
+        }
+        entries.push((entry.header().clone(), path, raw));
+    }
+    if removed.is_empty() {
+        return Err(RemoveError::NotFound(names.join(", ")));
+    }
+    // The "<pkgname>-<version>/" dir entry itself and its files
+    let is_removed = |path: &Path| {
+        removed_dirs.contains(path)
+            || path
+                .parent()
+                .is_some_and(|parent| removed_dirs.contains(parent))
+    };
+    for (mut header, path, raw) in entries {
+        if !is_removed(&path) {
+            tar_new_db.append_data(&mut header, path, raw.as_slice())?;
+        }
+    }
+
+    if files_path.exists() {
+        let mut archive = open_db(&files_path)?;
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.to_path_buf();
+            if is_removed(&path) {
+                continue;
+            }
+            let mut header = entry.header().clone();
+            tar_new_files.append_data(&mut header, path, BufReader::new(entry))?;
+        }
+    }
+    write_dbs::<RemoveError>(conf, target, &repo_lock, tar_new_db, tar_new_files)?;
+
+    for desc in &removed {
+        retire_pkgfile(conf, target, desc);
+    }
+    drop(repo_lock);
+    Ok(removed)
+}
+
+/// Re-register an archived package in the db, the latest archived one without version.
+/// The replaced package is archived in turn. The restored entry is held, see is_outdated.
+pub fn rollback(
+    conf: &Conf,
+    target: &Target,
+    name: &str,
+    version: Option<&str>,
+) -> Result<Version, AddError> {
+    let repo_lock = lock_repo(conf, target).map_err(AddError::DbLockError)?;
+    let Some((_, version, file)) = archived(conf, target)?
+        .into_iter()
+        .filter(|(pkgname, ..)| pkgname == name)
+        .find(|(_, v, _)| version.is_none_or(|version| v.to_string() == version))
+    else {
+        return Err(AddError::NotArchived(match version {
+            Some(version) => format!("{}-{}", name, version),
+            None => name.to_string(),
+        }));
+    };
+    let archive_dir = conf.archive_dir(target);
+    let fingerprint_file = fingerprint_path(&archive_dir.join(&file));
+    let fingerprint = fs::read_to_string(&fingerprint_file)
+        .ok()
+        .map(|fingerprint| fingerprint.trim().to_string());
+    let repo_dir = conf.repo_dir(target);
+    move_pkgfile(&archive_dir, &repo_dir, &file)?;
+    let pkgfile = repo_dir.join(&file).to_string_lossy().to_string();
+    let res = read_package(&pkgfile).and_then(|(pkginfo, csize, sha256, files)| {
+        sign::sign(conf, Path::new(&pkgfile))?;
+        // The next update would rebuild the newer PKGBUILD version
+        add_entries(
+            conf,
+            target,
+            &repo_lock,
+            vec![(pkgfile, pkginfo, csize, sha256, files)],
+            true,
+            |desc| {
+                desc.fingerprint = fingerprint.clone();
+                desc.hold = true;
+            },
+        )
+    });
+    if let Err(e) = res {
+        // Keep it archived for the next rollback
+        if let Err(e) = move_pkgfile(&repo_dir, &archive_dir, &file) {
+            error!("[{}] Failed to archive {} back: {}", name, file, e);
+        }
+        return Err(e);
+    }
+    fs::remove_file(fingerprint_file).ok();
+    Ok(version)
+}
+
+/// Clear the hold of a rolled back package, the next update replaces it. None when it is not
+/// held.
+pub fn unhold(conf: &Conf, target: &Target, name: &str) -> Result<Option<Version>, AddError> {
+    let repo_lock = lock_repo(conf, target).map_err(AddError::DbLockError)?;
+    let held = match list(conf, target) {
+        Ok(pkgs) => pkgs.into_iter().find(|desc| desc.name == name && desc.hold),
+        Err(RepoError::NoRepo) => None,
+        Err(e) => Err(AddError::Parsing(e.to_string()))?,
+    };
+    let Some(held) = held else {
+        return Ok(None);
+    };
+    let pkgfile = conf.repo_dir(target).join(&held.filename);
+    let pkgfile = pkgfile.to_string_lossy().to_string();
+    let (pkginfo, csize, sha256, files) = read_package(&pkgfile)?;
+    add_entries(
+        conf,
+        target,
+        &repo_lock,
+        vec![(pkgfile, pkginfo, csize, sha256, files)],
+        true,
+        |desc| desc.fingerprint = held.fingerprint.clone(),
+    )?;
+    Ok(Some(held._version))
+}
+
+// The repo package is the version or a local pkgrel bump of it
+fn is_same_release(dbpkg: &DbDesc, pkg: &SrcInfo) -> bool {
+    dbpkg.get_version() == pkg.get_version()
+        || pkgrel::is_rebuild_of(dbpkg.get_version(), pkg.get_version())
+}
+
+/// Repo package of the same release as pkg, see pkgrel::bump
+pub fn same_release<'a>(dbpkgs: &'a [DbDesc], pkg: &SrcInfo) -> Option<&'a DbDesc> {
+    dbpkgs
+        .iter()
+        .find(|dbpkg| dbpkg.name == pkg.name && is_same_release(dbpkg, pkg))
+}
+
+/// Whether the repo package of pkg must be rebuilt: new version, USE flags or build inputs.
+/// A held (rolled back) package is never outdated.
+pub fn is_outdated(
+    dbpkgs: &[DbDesc],
+    pkg: &SrcInfo,
+    use_flags: &[String],
+    fingerprint: Option<&str>,
+) -> bool {
+    for dbpkg in dbpkgs {
+        if dbpkg.name == pkg.name {
+            if dbpkg.hold {
+                info!(
+                    "[{}] {} held by a rollback, see rollback --unhold",
+                    dbpkg.name, dbpkg.version
+                );
+                return false;
+            }
+            return !is_same_release(dbpkg, pkg)
+                || dbpkg.use_flags != use_flags
+                || dbpkg.fingerprint.as_deref() != fingerprint;
+        }
+    }
+    false
 }
 
 #[cfg(test)]
@@ -503,29 +1279,368 @@ mod tests {
     #[test]
     fn add_items_to_db() {
         let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
-        assert!(matches!(list(&conf).unwrap_err(), RepoError::NoRepo));
+        let target = Target::default();
+        assert!(matches!(
+            list(&conf, &target).unwrap_err(),
+            RepoError::NoRepo
+        ));
         let pkgsdir = conf.pkgs_dir();
-        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1", false).unwrap();
-        add(&conf, &[pkginfo1]).unwrap();
-        let pkg_list = list(&conf).unwrap();
+        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1").unwrap();
+        add(&conf, &target, &[pkginfo1]).unwrap();
+        let pkg_list = list(&conf, &target).unwrap();
         assert_eq!(pkg_list.len(), 1);
         let entry = pkg_list.get(0).unwrap();
         assert_eq!(entry.name, "fake_pkg1", "Checking entry name");
         assert_eq!(entry.version, "2024.04.07-2");
-        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2", false).unwrap();
-        add(&conf, &[pkginfo2]).unwrap();
-        let pkg_list = list(&conf).unwrap();
+        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2").unwrap();
+        add(&conf, &target, &[pkginfo2]).unwrap();
+        let pkg_list = list(&conf, &target).unwrap();
         assert_eq!(pkg_list.len(), 2);
     }
+    #[test]
+    fn files_like_repo_add() {
+        // Golden output of repo-add for fake_pkg3 (directories, symlinks, hidden files)
+        let expected = fs::read_to_string("../resources/tests/fake_pkg3/files").unwrap();
+        let (_, _, _, files) =
+            read_package("../tmp/repo/fake_pkg3-2024.04.07-1-any.pkg.tar.zst").unwrap();
+        assert_eq!(
+            String::from_utf8(generate_files_file(files)).unwrap(),
+            expected
+        );
+
+        // Same listing as the repo-add pipeline for every fixture
+        for entry in fs::read_dir("../tmp/repo").unwrap() {
+            let path = entry.unwrap().path();
+            let Some(path) = path.to_str().filter(|p| p.contains(".pkg.tar")) else {
+                continue;
+            };
+            let out = std::process::Command::new("sh")
+                .arg("-c")
+                .arg("echo %FILES%; bsdtar --exclude='^.*' -tf \"$1\" | LC_ALL=C sort -u")
+                .args(["sh", path])
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "bsdtar failed on {}", path);
+            let (_, _, _, files) = read_package(path).unwrap();
+            assert_eq!(
+                String::from_utf8(generate_files_file(files)).unwrap(),
+                String::from_utf8(out.stdout).unwrap(),
+                "Testing {}",
+                path
+            );
+        }
+
+        // Stored as is in the files db
+        let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        let target = Target::default();
+        let pkginfo3 = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg3").unwrap();
+        add(&conf, &target, &[pkginfo3]).unwrap();
+        let mut archive = open_db(&conf.get_repo_files_db(&target)).unwrap();
+        let mut content = String::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new("fake_pkg3-2024.04.07-1/files") {
+                entry.read_to_string(&mut content).unwrap();
+            }
+        }
+        assert_eq!(content, expected);
+        let mut listed = Vec::new();
+        read_repo_files(&conf, &target, |file| listed.push(file.to_string())).unwrap();
+        assert_eq!(listed.len(), expected.lines().count() - 1);
+    }
+
+    #[test]
+    fn read_package_hash() {
+        for entry in fs::read_dir("../tmp/repo").unwrap() {
+            let path = entry.unwrap().path();
+            if !path.to_string_lossy().contains(".pkg.tar") {
+                continue;
+            }
+            let content = fs::read(&path).unwrap();
+            let (_, csize, sha256, _) = read_package(&path.to_string_lossy()).unwrap();
+            assert_eq!(csize, content.len() as u64, "Testing {:?}", path);
+            assert_eq!(
+                sha256,
+                base16ct::lower::encode_string(&Sha256::digest(&content)),
+                "Testing {:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn check_and_rebuild() {
+        let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        let target = Target::default();
+        let repo_dir = conf.repo_dir(&target);
+        let pkgsdir = conf.pkgs_dir();
+        let pkg1 = "fake_pkg1-2024.04.07-2-any.pkg.tar.zst";
+        let pkg2 = "fake_pkg2-2024.04.07-2-any.pkg.tar.zst";
+        let pkg3 = "fake_pkg3-2024.04.07-1-any.pkg.tar.zst";
+        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1").unwrap();
+        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2").unwrap();
+        add(&conf, &target, &[pkginfo1, pkginfo2]).unwrap();
+        assert_eq!(
+            check(&conf, &target).unwrap(),
+            [RepoIssue::Orphan(pkg3.to_string())]
+        );
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(repo_dir.join(pkg1))
+            .unwrap();
+        file.write_all(b"garbage").unwrap();
+        fs::remove_file(repo_dir.join(pkg2)).unwrap();
+        let issues = check(&conf, &target).unwrap();
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        assert!(matches!(&issues[0], RepoIssue::Corrupted { file, .. } if file == pkg1));
+        assert_eq!(
+            issues[1],
+            RepoIssue::Missing {
+                name: "fake_pkg2".to_string(),
+                file: pkg2.to_string()
+            }
+        );
+
+        // Corrupted db, an older fake_pkg1 is skipped
+        fs::write(conf.get_repo_db(&target), "garbage".repeat(200)).unwrap();
+        assert!(check(&conf, &target).is_err());
+        let old = "fake_pkg1-2024.04.06-1-any.pkg.tar.zst";
+        fs::copy(repo_dir.join(pkg3), repo_dir.join(old)).unwrap();
+        assert_eq!(rebuild(&conf, &target).unwrap(), [pkg1, pkg3]);
+        let mut names: Vec<String> = list(&conf, &target)
+            .unwrap()
+            .into_iter()
+            .map(|desc| desc.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["fake_pkg1", "fake_pkg3"]);
+        assert_eq!(
+            check(&conf, &target).unwrap(),
+            [RepoIssue::Orphan(old.to_string())]
+        );
+    }
+
     #[test]
     fn add_2_items_to_db() {
         let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
-        assert!(matches!(list(&conf).unwrap_err(), RepoError::NoRepo));
+        let target = Target::default();
+        assert!(matches!(
+            list(&conf, &target).unwrap_err(),
+            RepoError::NoRepo
+        ));
         let pkgsdir = conf.pkgs_dir();
-        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1", false).unwrap();
-        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2", false).unwrap();
-        add(&conf, &[pkginfo1, pkginfo2]).unwrap();
-        let pkg_list = list(&conf).unwrap();
+        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1").unwrap();
+        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2").unwrap();
+        add(&conf, &target, &[pkginfo1, pkginfo2]).unwrap();
+        let pkg_list = list(&conf, &target).unwrap();
         assert_eq!(pkg_list.len(), 2);
     }
+
+    #[test]
+    fn db_compression() {
+        let mut conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        conf.repo_name = "custom".to_string();
+        conf.targets[0].name = "custom".to_string();
+        conf.db_compression = Compression::Zst;
+        let target = conf.targets[0].clone();
+        let repo_dir = conf.repo_dir(&target);
+        assert_eq!(repo_dir, conf.server_dir.join("repo"));
+        let pkginfo1 = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg1").unwrap();
+        add(&conf, &target, &[pkginfo1]).unwrap();
+        for (link, archive) in [
+            ("custom.db", "custom.db.tar.zst"),
+            ("custom.files", "custom.files.tar.zst"),
+        ] {
+            assert_eq!(
+                fs::read_link(repo_dir.join(link)).unwrap(),
+                Path::new(archive)
+            );
+        }
+        assert_eq!(list(&conf, &target).unwrap().len(), 1);
+
+        // The old archives are replaced
+        conf.db_compression = Compression::Xz;
+        let pkginfo2 = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg2").unwrap();
+        add(&conf, &target, &[pkginfo2]).unwrap();
+        assert_eq!(
+            fs::read_link(repo_dir.join("custom.db")).unwrap(),
+            Path::new("custom.db.tar.xz")
+        );
+        assert!(!repo_dir.join("custom.db.tar.zst").exists());
+        assert!(!repo_dir.join("custom.files.tar.zst").exists());
+        assert_eq!(list(&conf, &target).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn downgrade() {
+        let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        let target = Target::default();
+        let pkginfo1 = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg1").unwrap();
+        add(&conf, &target, std::slice::from_ref(&pkginfo1)).unwrap();
+
+        let pkgfile = find_pkgfile(&conf.repo_dir(&target), &pkginfo1).unwrap();
+        let pkgfile = conf.repo_dir(&target).join(pkgfile);
+        let pkgfile = pkgfile.to_string_lossy().to_string();
+        let (mut pkginfo, csize, sha256, files) = read_package(&pkgfile).unwrap();
+        pkginfo.version = Version::new("2024.01.01", Some("1"), None);
+        let adding = [(pkgfile, pkginfo, csize, sha256, files)];
+        let mut to_remove = Vec::new();
+        let mut out = tar::Builder::new(Vec::new());
+        let repo_path = conf.get_repo_db(&target);
+        assert!(matches!(
+            copy_old_db(&mut out, &repo_path, false, &adding, &mut to_remove),
+            Err(AddError::Downgrade { .. })
+        ));
+        copy_old_db(&mut out, &repo_path, true, &adding, &mut to_remove).unwrap();
+        assert_eq!(
+            to_remove.iter().map(|d| &d.filename).collect::<Vec<_>>(),
+            [&find_pkgfile(&conf.repo_dir(&target), &pkginfo1).unwrap()]
+        );
+    }
+
+    #[test]
+    fn split_pkgfiles() {
+        let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        let repo_dir = conf.repo_dir(&Target::default());
+        let mut pkginfo = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg1").unwrap();
+        let file = find_pkgfile(&repo_dir, &pkginfo).unwrap();
+        assert_eq!(find_pkgfiles(&repo_dir, &pkginfo), Some(vec![file.clone()]));
+        pkginfo.pkgnames = vec!["fake_pkg1".to_string(), "fake_pkg1-docs".to_string()];
+        assert_eq!(find_pkgfiles(&repo_dir, &pkginfo), None);
+        assert!(!has_pkgfiles(std::slice::from_ref(&file), &pkginfo));
+        let docs = file.replacen("fake_pkg1", "fake_pkg1-docs", 1);
+        assert!(has_pkgfiles(&[file, docs], &pkginfo));
+    }
+
+    #[test]
+    fn archive_and_rollback() {
+        let mut conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        conf.keep_versions = 1;
+        let target = Target::default();
+        let mut pkginfo1 = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg1").unwrap();
+        let file = find_pkgfile(&conf.repo_dir(&target), &pkginfo1).unwrap();
+        assert!(has_pkgfiles(std::slice::from_ref(&file), &pkginfo1));
+        pkginfo1.fingerprint = Some("inputs".to_string());
+        add(&conf, &target, std::slice::from_ref(&pkginfo1)).unwrap();
+        remove(&conf, &target, &["fake_pkg1".to_string()]).unwrap();
+        let archive_dir = conf.archive_dir(&target);
+        assert!(archive_dir.join(&file).exists());
+        assert!(fingerprint_path(&archive_dir.join(&file)).exists());
+        let version = Version::new("2024.04.07", Some("2"), None);
+        assert_eq!(
+            archived(&conf, &target).unwrap(),
+            [("fake_pkg1".to_string(), version.clone(), file.clone())]
+        );
+
+        assert!(matches!(
+            rollback(&conf, &target, "fake_pkg1", Some("1.0-1")),
+            Err(AddError::NotArchived(_))
+        ));
+        // Still archived when the db update fails
+        let lock = DirLock::new(conf.get_repo_db(&target).with_extension("lock"), false).unwrap();
+        assert!(matches!(
+            rollback(&conf, &target, "fake_pkg1", None),
+            Err(AddError::DbLockError(_))
+        ));
+        drop(lock);
+        assert!(archive_dir.join(&file).exists());
+        assert!(!conf.repo_dir(&target).join(&file).exists());
+        assert_eq!(
+            rollback(&conf, &target, "fake_pkg1", None).unwrap(),
+            version
+        );
+        let desc = list(&conf, &target).unwrap().remove(0);
+        assert_eq!(desc.version, "2024.04.07-2");
+        assert_eq!(desc.fingerprint.as_deref(), Some("inputs"));
+        assert!(desc.hold);
+        assert!(archived(&conf, &target).unwrap().is_empty());
+        assert!(!fingerprint_path(&archive_dir.join(&file)).exists());
+
+        // Held: the newer PKGBUILD version is not rebuilt until unhold
+        let mut newer = SrcInfo::new(&conf.pkgs_dir(), "fake_pkg1").unwrap();
+        newer.set_pkgrel("3");
+        let dbpkgs = list(&conf, &target).unwrap();
+        assert!(!is_outdated(&dbpkgs, &newer, &[], Some("inputs")));
+        assert_eq!(
+            unhold(&conf, &target, "fake_pkg1").unwrap(),
+            Some(version.clone())
+        );
+        assert_eq!(unhold(&conf, &target, "fake_pkg1").unwrap(), None);
+        let dbpkgs = list(&conf, &target).unwrap();
+        assert!(!dbpkgs[0].hold);
+        assert_eq!(dbpkgs[0].fingerprint.as_deref(), Some("inputs"));
+        assert!(is_outdated(&dbpkgs, &newer, &[], Some("inputs")));
+        assert!(!is_outdated(&dbpkgs, &pkginfo1, &[], Some("inputs")));
+
+        // Only the newest keep_versions are kept
+        for old in [
+            "fake_pkg1-1.0-1-any.pkg.tar.zst",
+            "fake_pkg1-2.0-1-any.pkg.tar.zst",
+        ] {
+            fs::write(archive_dir.join(old), "").unwrap();
+        }
+        retire_pkgfile(&conf, &target, &desc);
+        assert_eq!(
+            archived(&conf, &target).unwrap(),
+            [("fake_pkg1".to_string(), version, file)]
+        );
+    }
+
+    #[test]
+    fn remove_item_from_db() {
+        let conf = Conf::_test_builder().server_dir("../tmp".into()).call();
+        let target = Target::default();
+        let pkgsdir = conf.pkgs_dir();
+        let pkginfo1 = SrcInfo::new(&pkgsdir, "fake_pkg1").unwrap();
+        let pkginfo2 = SrcInfo::new(&pkgsdir, "fake_pkg2").unwrap();
+        add(&conf, &target, &[pkginfo1, pkginfo2]).unwrap();
+        // repo-add also writes the "<pkgname>-<version>/" dir entries
+        let repo_lock = DirLock::new(conf.get_repo_db(&target).with_extension("lock"), false);
+        let (mut tar_db, mut tar_files) = new_dbs();
+        for (tar, link) in [
+            (&mut tar_db, conf.get_repo_db(&target)),
+            (&mut tar_files, conf.get_repo_files_db(&target)),
+        ] {
+            for entry in open_db(&readable(&conf, &link)).unwrap().entries().unwrap() {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().to_path_buf();
+                if path.ends_with("desc") {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    tar.append_data(&mut header, path.parent().unwrap(), io::empty())
+                        .unwrap();
+                }
+                let mut header = entry.header().clone();
+                tar.append_data(&mut header, path, entry).unwrap();
+            }
+        }
+        write_dbs::<AddError>(&conf, &target, &repo_lock.unwrap(), tar_db, tar_files).unwrap();
+        assert!(matches!(
+            remove(&conf, &target, &["unknown".to_string()]).unwrap_err(),
+            RemoveError::NotFound(_)
+        ));
+        let removed = remove(&conf, &target, &["fake_pkg1".to_string()]).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!conf.repo_dir(&target).join(&removed[0].filename).exists());
+        let pkg_list = list(&conf, &target).unwrap();
+        assert_eq!(pkg_list.len(), 1);
+        assert_eq!(pkg_list[0].name, "fake_pkg2");
+        let mut files = Vec::new();
+        read_repo_files(&conf, &target, |file| files.push(file.to_string())).unwrap();
+        assert!(!files.is_empty());
+        assert!(files.iter().all(|file| !file.contains("fake_pkg1")));
+        for link in [conf.get_repo_db(&target), conf.get_repo_files_db(&target)] {
+            let mut dirs = Vec::new();
+            for entry in open_db(&readable(&conf, &link)).unwrap().entries().unwrap() {
+                let entry = entry.unwrap();
+                if entry.header().entry_type().is_dir() {
+                    dirs.push(entry.path().unwrap().to_string_lossy().to_string());
+                }
+            }
+            assert_eq!(dirs.len(), 1);
+            assert!(dirs[0].starts_with("fake_pkg2-"));
+        }
+    }
 }
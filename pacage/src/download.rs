@@ -10,6 +10,9 @@ use crate::cmd::{command, CmdError, ExecError};
 use crate::conf::{Conf, PkgsDir};
 use crate::conf::{Package, Repo};
 use crate::format::{ParsingError, SrcInfo};
+use crate::kconfig;
+use crate::pkgrel;
+use crate::use_flags::{UseError, UseFlags};
 use thiserror::Error;
 
 // TODO: git goes brr: git clone --filter=tree:0 <repo>
@@ -32,6 +35,15 @@ pub enum DownloadError {
 
     #[error("Missing PKGBUILD: {0}")]
     MissingPkgbuild(io::Error),
+
+    #[error("USE flags error: {0}")]
+    Use(#[from] UseError),
+
+    #[error("Kernel config error: {0}")]
+    Kconfig(io::Error),
+
+    #[error("Failed to set the pkgrel: {0}")]
+    Pkgrel(io::Error),
 }
 
 // IO error
@@ -73,7 +85,7 @@ pub fn fetch_pkg(pkgs_dir: &PkgsDir, name: &str, repo: &Repo) -> Result<SrcInfo,
         }
     };
     if status.success() {
-        Ok(SrcInfo::new(pkgs_dir, name, false)?)
+        Ok(SrcInfo::new(pkgs_dir, name)?)
     } else {
         Err(DownloadError::NotFound(out))?
     }
@@ -178,14 +190,26 @@ pub fn download_all<'a>(
                         done.insert(name.clone());
                     }
                     info!("[{}] Downloading...", name);
-                    let (need_deps, pkg) = {
+                    let (need_deps, pkg, use_flags) = {
                         let mut conf = conf.lock().unwrap();
                         conf.ensure_pkg(name.as_str());
                         let pkg = conf.get(name.as_str()).clone();
                         let need_deps = conf.need_deps(&pkg);
-                        (need_deps, pkg)
+                        let use_flags = UseFlags::new(&conf, &pkg.name);
+                        (need_deps, pkg, use_flags)
                     };
-                    let pkg_build = match fetch_pkg(pkgs_dir, &name, &pkg.repo) {
+                    // USE flags are applied on the freshly fetched PKGBUILD
+                    let pkg_build = match use_flags
+                        .map_err(DownloadError::from)
+                        .and_then(|flags| {
+                            Ok(flags.apply(pkgs_dir, fetch_pkg(pkgs_dir, &name, &pkg.repo)?)?)
+                        })
+                        .and_then(|mut srcinfo| {
+                            let conf = conf.lock().unwrap();
+                            pkgrel::apply(&conf, &mut srcinfo).map_err(DownloadError::Pkgrel)?;
+                            kconfig::apply(&conf, &pkg.name).map_err(DownloadError::Kconfig)?;
+                            Ok(srcinfo)
+                        }) {
                         Ok(p) => p,
                         Err(e) => {
                             if continue_on_err {
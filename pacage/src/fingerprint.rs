@@ -0,0 +1,191 @@
+/*
+Build inputs fingerprint, stored in the repo db (%PACAGE_FINGERPRINT%), sha256 of:
+- the PKGBUILD tree (git tracked files but the .SRCINFO, with the pacage changes: USE flags,
+  kernel config, not the local pkgrel: its bumps are rebuilds with the same inputs)
+- the patches
+- the effective makepkg.conf, makepkg flags, build environment and PGO training
+- the builder image registry digest (see image_digest)
+A package is rebuilt when it changes, even without a new version.
+*/
+
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use thiserror::Error;
+
+use crate::builder::BUILDER_IMAGE;
+use crate::conf::{Conf, Makepkg, Package, Target};
+use crate::patch::{get_patches, PatchError};
+use crate::pkgrel;
+
+#[derive(Debug, Error)]
+pub enum FingerprintError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to list the PKGBUILD files: {0}")]
+    Git(String),
+
+    #[error("Patch error: {0}")]
+    Patch(#[from] PatchError),
+}
+
+/// Registry digest of the builder image, the same on every server that pulled it, the local
+/// image id for an image that was not pulled. None if it is not pulled yet.
+pub fn image_digest(conf: &Conf) -> Option<String> {
+    let out = Command::new(&conf.container_runner)
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{range .RepoDigests}}{{println .}}{{end}}{{.Id}}",
+            BUILDER_IMAGE,
+        ])
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    // "<repo>@sha256:<digest>" lines then the id
+    let out = String::from_utf8_lossy(&out.stdout);
+    let first = out.lines().map(str::trim).find(|line| !line.is_empty())?;
+    Some(first.rsplit('@').next().unwrap_or(first).to_string())
+}
+
+// Length prefixed, "ab" + "c" and "a" + "bc" give different hashes
+fn update(hasher: &mut Sha256, name: &str, content: &[u8]) {
+    for part in [name.as_bytes(), content] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+}
+
+fn pkgbuild_files(pkg_dir: &Path) -> Result<Vec<String>, FingerprintError> {
+    let out = Command::new("git")
+        .args(["ls-files", "-z"])
+        .current_dir(pkg_dir)
+        .output()?;
+    if !out.status.success() {
+        return Err(FingerprintError::Git(
+            String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        ));
+    }
+    let mut files: Vec<String> = out
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|file| !file.is_empty())
+        .map(|file| String::from_utf8_lossy(file).to_string())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+pub fn compute(
+    conf: &Conf,
+    pkg: &Package,
+    target: &Target,
+    image: Option<&str>,
+) -> Result<String, FingerprintError> {
+    let mut hasher = Sha256::new();
+    let pkg_dir = conf.pkgs_dir().pkg(&pkg.name);
+    for file in pkgbuild_files(&pkg_dir)? {
+        let path = pkg_dir.join(&file);
+        // The .SRCINFO is generated from the PKGBUILD, deleted files could still be staged
+        if file == ".SRCINFO" || !path.is_file() {
+            continue;
+        }
+        let mut content = fs::read(path)?;
+        if file == "PKGBUILD" {
+            content = pkgrel::strip(&String::from_utf8_lossy(&content)).into_bytes();
+        }
+        update(&mut hasher, &file, &content);
+    }
+    for patch in get_patches(conf, &pkg.name)?.unwrap_or_default() {
+        let path = Path::new(&patch);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        update(&mut hasher, &name, &fs::read(path)?);
+    }
+    let resolved = Makepkg::resolve(conf, pkg, target)?;
+    update(
+        &mut hasher,
+        "makepkg.conf",
+        resolved.file.to_string().as_bytes(),
+    );
+    update(&mut hasher, "flags", resolved.flags.join(" ").as_bytes());
+    for (var, value) in &resolved.env {
+        update(&mut hasher, var, value.as_bytes());
+    }
+    update(
+        &mut hasher,
+        "packages",
+        resolved.packages.join(" ").as_bytes(),
+    );
+    if let Some(pgo) = &pkg.pgo {
+        update(&mut hasher, "pgo", pgo.train.as_bytes());
+    }
+    update(&mut hasher, "image", image.unwrap_or_default().as_bytes());
+    Ok(base16ct::lower::encode_string(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::Repo;
+
+    #[test]
+    fn inputs() {
+        let mut conf = Conf::rand();
+        let name = format!("pacage-fingerprint-{}", std::process::id());
+        let pkg_dir = conf.pkgs_dir().pkg(&name);
+        fs::create_dir_all(&pkg_dir).unwrap();
+        fs::write(pkg_dir.join("PKGBUILD"), "pkgname=foo\npkgver=1\n").unwrap();
+        let git = |args: &[&str]| {
+            assert!(Command::new("git")
+                .args(args)
+                .current_dir(&pkg_dir)
+                .output()
+                .unwrap()
+                .status
+                .success())
+        };
+        git(&["init", "-q"]);
+        git(&["add", "PKGBUILD"]);
+        let mut pkg = Package {
+            name: name.clone(),
+            makepkg: None,
+            deps: None,
+            repo: Repo::None,
+            profile: None,
+            use_flags: None,
+            env: None,
+            pgo: None,
+            pkgrel_suffix: None,
+        };
+        let target = Target::default();
+        let fingerprint =
+            |conf: &Conf, pkg: &Package, image| compute(conf, pkg, &target, image).unwrap();
+
+        let base = fingerprint(&conf, &pkg, Some("sha256:1"));
+        assert_eq!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+        // Untracked files are ignored
+        fs::write(pkg_dir.join("src.tar.gz"), "").unwrap();
+        assert_eq!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+
+        assert_ne!(base, fingerprint(&conf, &pkg, Some("sha256:2")));
+        pkg.env = Some([("FOO".to_string(), "1".to_string())].into());
+        assert_ne!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+        pkg.env = None;
+        conf.makepkg = Some(toml::from_str("cflags = \"-O3\"").unwrap());
+        assert_ne!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+        conf.makepkg = None;
+        // Local pkgrel bumps are rebuilds with the same inputs
+        let bumped = "pkgname=foo\npkgver=1\n\n# pacage: pkgrel 1\npkgrel=1.1\n";
+        fs::write(pkg_dir.join("PKGBUILD"), bumped).unwrap();
+        assert_eq!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+        fs::write(pkg_dir.join("PKGBUILD"), "pkgname=foo\npkgver=2\n").unwrap();
+        assert_ne!(base, fingerprint(&conf, &pkg, Some("sha256:1")));
+        fs::remove_dir_all(pkg_dir).ok();
+    }
+}
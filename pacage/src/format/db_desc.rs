@@ -91,6 +91,10 @@ mod desc {
     pub const OPTDEPENDS: &str = "%OPTDEPENDS%";
     pub const MAKEDEPENDS: &str = "%MAKEDEPENDS%";
     pub const CHECKDEPENDS: &str = "%CHECKDEPENDS%";
+    // Extension
+    pub const USE: &str = "%PACAGE_USE%";
+    pub const FINGERPRINT: &str = "%PACAGE_FINGERPRINT%";
+    pub const HOLD: &str = "%PACAGE_HOLD%";
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -120,6 +124,13 @@ pub struct DbDesc {
     pub optdepends: Vec<String>,
     pub makedepends: Vec<String>,
     pub checkdepends: Vec<String>,
+    // Extension
+    // Effective USE flags of the build
+    pub use_flags: Vec<String>,
+    // Build inputs fingerprint, see fingerprint.rs
+    pub fingerprint: Option<String>,
+    // Rolled back, not updated until the hold is cleared
+    pub hold: bool,
 }
 
 fn get_val_string(
@@ -213,6 +224,9 @@ impl DbDesc {
         let mut optdepends = Vec::new();
         let mut makedepends = Vec::new();
         let mut checkdepends = Vec::new();
+        let mut use_flags = Vec::new();
+        let mut fingerprint = None;
+        let mut hold = false;
         let mut lines = data.lines();
         while let Some(line) = lines.next() {
             if let Ok(line) = line {
@@ -249,6 +263,11 @@ impl DbDesc {
                         checkdepends = get_val_vec_string(&mut lines, desc::CHECKDEPENDS)?
                     }
                     // Extension
+                    desc::USE => use_flags = get_val_vec_string(&mut lines, desc::USE)?,
+                    desc::FINGERPRINT => {
+                        fingerprint = Some(get_val_string(&mut lines, desc::FINGERPRINT)?)
+                    }
+                    desc::HOLD => hold = get_val_string(&mut lines, desc::HOLD)? == "1",
                     a => warn!("DB desc unknown property: {}", a),
                 }
             }
@@ -310,6 +329,9 @@ impl DbDesc {
             optdepends,
             makedepends,
             checkdepends,
+            use_flags,
+            fingerprint,
+            hold,
         })
     }
 
@@ -356,6 +378,7 @@ impl DbDesc {
             (&self.url, desc::URL),
             (&self.arch, desc::ARCH),
             (&self.packager, desc::PACKAGER),
+            (&self.fingerprint, desc::FINGERPRINT),
         ] {
             if let Some(value) = value {
                 writer.write(b"\n\n")?;
@@ -364,6 +387,9 @@ impl DbDesc {
                 writer.write(value.as_bytes())?;
             }
         }
+        if self.hold {
+            writer.write_all(format!("\n\n{}\n1", desc::HOLD).as_bytes())?;
+        }
         for (list, key) in [
             (&self.groups, desc::GROUPS),
             (&self.licenses, desc::LICENSE),
@@ -374,6 +400,7 @@ impl DbDesc {
             (&self.optdepends, desc::OPTDEPENDS),
             (&self.makedepends, desc::MAKEDEPENDS),
             (&self.checkdepends, desc::CHECKDEPENDS),
+            (&self.use_flags, desc::USE),
         ] {
             Self::write_list(list, &mut writer, key)?;
         }
@@ -420,6 +447,9 @@ mod tests {
             makedepends: vec![],
             checkdepends: vec!["testsss".to_string()],
             // Extension
+            use_flags: vec!["-x11".to_string(), "wayland".to_string()],
+            fingerprint: Some("0123456789abcdef".to_string()),
+            hold: true,
         };
         let mut data = Vec::new();
         orig.write(&mut data).unwrap();
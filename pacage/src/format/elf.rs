@@ -0,0 +1,164 @@
+/*
+Minimal ELF reader, only the dynamic section is read (readelf -d):
+ 0x0000000000000001 (NEEDED)             Shared library: [libncursesw.so.6]
+ 0x0000000000000001 (NEEDED)             Shared library: [libc.so.6]
+ 0x000000000000000e (SONAME)             Library soname: [libfoo.so.1]
+The section headers are used to find it, they are kept by strip.
+*/
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const SHT_DYNAMIC: u32 = 6;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_SONAME: u64 = 14;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ElfDynamic {
+    pub soname: Option<String>,
+    pub needed: Vec<String>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is64: bool,
+    le: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.data
+            .get(offset..offset.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let b = self.bytes(offset)?;
+        Some(if self.le {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let b = self.bytes(offset)?;
+        Some(if self.le {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let b = self.bytes(offset)?;
+        Some(if self.le {
+            u64::from_le_bytes(b)
+        } else {
+            u64::from_be_bytes(b)
+        })
+    }
+
+    // Address/offset sized value
+    fn word(&self, offset: usize) -> Option<u64> {
+        if self.is64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    fn str(&self, offset: usize) -> Option<String> {
+        let data = self.data.get(offset..)?;
+        let end = data.iter().position(|c| *c == 0)?;
+        Some(String::from_utf8_lossy(&data[..end]).to_string())
+    }
+}
+
+// (type, offset, size, link) of a section header
+fn section(r: &Reader, offset: usize) -> Option<(u32, usize, usize, u32)> {
+    // Offsets from the file, could overflow
+    let at = |n: usize| offset.checked_add(n);
+    let typ = r.u32(at(4)?)?;
+    let (sh_offset, sh_size, sh_link) = if r.is64 {
+        (r.u64(at(24)?)?, r.u64(at(32)?)?, r.u32(at(40)?)?)
+    } else {
+        (
+            r.u32(at(16)?)? as u64,
+            r.u32(at(20)?)? as u64,
+            r.u32(at(24)?)?,
+        )
+    };
+    Some((
+        typ,
+        usize::try_from(sh_offset).ok()?,
+        usize::try_from(sh_size).ok()?,
+        sh_link,
+    ))
+}
+
+impl ElfDynamic {
+    /// None if this is not an ELF file or there is no dynamic section (static binary)
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.get(..4)? != ELF_MAGIC {
+            return None;
+        }
+        let r = Reader {
+            data,
+            is64: *data.get(4)? == 2,
+            le: *data.get(5)? == 1,
+        };
+        let (shoff, shentsize, shnum) = if r.is64 {
+            (r.u64(0x28)?, r.u16(0x3A)?, r.u16(0x3C)?)
+        } else {
+            (r.u32(0x20)? as u64, r.u16(0x2E)?, r.u16(0x30)?)
+        };
+        let shoff = usize::try_from(shoff).ok()?;
+        let header = |i: usize| section(&r, shoff.checked_add(i.checked_mul(shentsize as usize)?)?);
+        let (_, dyn_offset, dyn_size, dyn_link) = (0..shnum as usize)
+            .filter_map(&header)
+            .find(|(typ, ..)| *typ == SHT_DYNAMIC)?;
+        let (_, strtab, ..) = header(dyn_link as usize)?;
+
+        let entry_size = if r.is64 { 16 } else { 8 };
+        let mut res = Self::default();
+        for offset in (dyn_offset..dyn_offset.saturating_add(dyn_size)).step_by(entry_size) {
+            let tag = r.word(offset)?;
+            let val = usize::try_from(r.word(offset.checked_add(entry_size / 2)?)?).ok()?;
+            match tag {
+                DT_NULL => break,
+                DT_NEEDED => res.needed.push(r.str(strtab.checked_add(val)?)?),
+                DT_SONAME => res.soname = Some(r.str(strtab.checked_add(val)?)?),
+                _ => {}
+            }
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn dynamic() {
+        assert_eq!(ElfDynamic::parse(b"#!/bin/sh\n"), None);
+        // The test binary itself is linked against the libc
+        let exe = fs::read(std::env::current_exe().unwrap()).unwrap();
+        let dynamic = ElfDynamic::parse(&exe).unwrap();
+        assert_eq!(dynamic.soname, None);
+        assert!(dynamic.needed.iter().any(|lib| lib.starts_with("libc.so")));
+
+        // Malformed section headers offset
+        let mut elf = vec![0u8; 64];
+        elf[..4].copy_from_slice(&ELF_MAGIC);
+        elf[4] = 2;
+        elf[5] = 1;
+        elf[0x28..0x30].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+        elf[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3C..0x3E].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(ElfDynamic::parse(&elf), None);
+    }
+}
@@ -0,0 +1,167 @@
+/*
+==== makepkg.conf ====
+CARCH="x86_64"
+CFLAGS="-march=x86-64 -mtune=generic -O2 -pipe -fno-plt -fexceptions \
+        -Wp,-D_FORTIFY_SOURCE=3 -Wformat -Werror=format-security"
+[...]
+OPTIONS=(strip docs !libtool !staticlibs emptydirs zipman purge !debug lto)
+[...]
+========
+*/
+
+// makepkg.conf is sourced by makepkg, only the top level assignments are handled here.
+// Values are kept as raw bash, quotes and parenthesis included.
+
+use std::fmt::Display;
+
+pub struct MakepkgConf {
+    lines: Vec<String>,
+}
+
+// Index of the last line of the value starting at lines[start][offset..]
+fn value_end(lines: &[String], start: usize, offset: usize) -> usize {
+    let mut quote = None;
+    let mut depth = 0;
+    for (n, line) in lines[start..].iter().enumerate() {
+        let text = if n == 0 { &line[offset..] } else { line };
+        let mut escaped = false;
+        let mut prev = ' ';
+        for c in text.chars() {
+            if escaped {
+                escaped = false;
+                prev = c;
+                continue;
+            }
+            match (quote, c) {
+                (Some('\''), '\'') => quote = None,
+                (Some('\''), _) => {}
+                (_, '\\') => escaped = true,
+                (Some(_), '"') => quote = None,
+                (Some(_), _) => {}
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (None, '(') => depth += 1,
+                (None, ')') => depth -= 1,
+                (None, '#') if prev.is_whitespace() => break,
+                _ => {}
+            }
+            prev = c;
+        }
+        if quote.is_none() && depth <= 0 && !escaped {
+            return start + n;
+        }
+    }
+    lines.len() - 1
+}
+
+impl MakepkgConf {
+    pub fn new(content: &str) -> Self {
+        Self {
+            lines: content.lines().map(|l| l.to_string()).collect(),
+        }
+    }
+
+    // (first line, last line) of each assignment of `key`
+    fn find(&self, key: &str) -> Vec<(usize, usize)> {
+        let prefix = format!("{}=", key);
+        let mut res = Vec::new();
+        let mut n = 0;
+        while n < self.lines.len() {
+            if self.lines[n].starts_with(&prefix) {
+                let end = value_end(&self.lines, n, prefix.len());
+                res.push((n, end));
+                n = end;
+            }
+            n += 1;
+        }
+        res
+    }
+
+    /// Raw value of the last assignment of `key`
+    pub fn get(&self, key: &str) -> Option<String> {
+        let (start, end) = *self.find(key).last()?;
+        let value = self.lines[start..=end].join("\n");
+        Some(value[(key.len() + 1)..].to_string())
+    }
+
+    /// Replace the assignments of `key` by `value` (raw bash), or append it
+    pub fn set(&mut self, key: &str, value: &str) {
+        let assignment = format!("{}={}", key, value);
+        let found = self.find(key);
+        let Some(&(start, end)) = found.first() else {
+            self.lines.push(assignment);
+            return;
+        };
+        for &(start, end) in found[1..].iter().rev() {
+            self.lines.drain(start..=end);
+        }
+        self.lines.splice(start..=end, [assignment]);
+    }
+
+    /// Append a raw bash line, ex: CFLAGS+=" -O3"
+    pub fn push(&mut self, line: &str) {
+        self.lines.push(line.to_string());
+    }
+}
+
+impl Display for MakepkgConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = r#"CARCH="x86_64"
+CFLAGS="-O2 -pipe \
+        -fno-plt"
+#OPTIONS=(commented)
+OPTIONS=(strip docs # comment (
+         !debug lto)
+DLAGENTS=('file::/usr/bin/curl -qgC - -o %o %u'
+          'https::/usr/bin/curl -qgb "" -fLC - --retry 3 -o %o %u')
+PKGEXT='.pkg.tar.zst'
+PKGEXT='.pkg.tar.xz'
+"#;
+
+    #[test]
+    fn get() {
+        let conf = MakepkgConf::new(CONF);
+        assert_eq!(conf.get("CARCH").as_deref(), Some("\"x86_64\""));
+        assert_eq!(
+            conf.get("CFLAGS").as_deref(),
+            Some("\"-O2 -pipe \\\n        -fno-plt\"")
+        );
+        assert_eq!(
+            conf.get("OPTIONS").as_deref(),
+            Some("(strip docs # comment (\n         !debug lto)")
+        );
+        assert_eq!(conf.get("PKGEXT").as_deref(), Some("'.pkg.tar.xz'"));
+        assert_eq!(conf.get("MAKEFLAGS"), None);
+    }
+
+    #[test]
+    fn set() {
+        let mut conf = MakepkgConf::new(CONF);
+        conf.set("CFLAGS", "\"-O3\"");
+        conf.set("OPTIONS", "(!strip)");
+        conf.set("PKGEXT", "'.pkg.tar'");
+        conf.set("MAKEFLAGS", "\"-j8\"");
+        assert_eq!(
+            conf.to_string(),
+            r#"CARCH="x86_64"
+CFLAGS="-O3"
+#OPTIONS=(commented)
+OPTIONS=(!strip)
+DLAGENTS=('file::/usr/bin/curl -qgC - -o %o %u'
+          'https::/usr/bin/curl -qgb "" -fLC - --retry 3 -o %o %u')
+PKGEXT='.pkg.tar'
+MAKEFLAGS="-j8"
+"#
+        );
+    }
+}
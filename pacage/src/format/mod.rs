@@ -1,10 +1,16 @@
 use thiserror::Error;
 
 mod db_desc;
+mod elf;
+mod makepkg_conf;
+mod pkgbuild;
 mod pkginfo;
 mod srcinfo;
 
 pub use db_desc::{DbDesc, DbDescError};
+pub use elf::{ElfDynamic, ELF_MAGIC};
+pub use makepkg_conf::MakepkgConf;
+pub use pkgbuild::{replace_section, PkgBuild, PkgBuildError};
 pub use pkginfo::{PkgInfo, PkgInfoError};
 pub use srcinfo::{SrcInfo, SrcInfoError};
 
@@ -14,6 +20,8 @@ pub enum ParsingError {
     SrcInfo(#[from] SrcInfoError),
     #[error("System error while parsing .PKGINFO : {0}")]
     PkgInfo(#[from] PkgInfoError),
+    #[error("System error while parsing PKGBUILD : {0}")]
+    PkgBuild(#[from] PkgBuildError),
     // #[error("System error while parsing .SRCINFO : {0}")]
     // RepoPackage(#[from] io::Error),
     // #[error("System error while parsing .SRCINFO : {0}")]
@@ -0,0 +1,1250 @@
+/*
+vi:
+==== PKGBUILD ====
+pkgname=vi
+epoch=1
+pkgver=070224
+pkgrel=6
+pkgdesc='The original ex/vi text editor'
+arch=('x86_64')
+depends=('ncurses')
+source=("https://sources.archlinux.org/other/vi/ex-${pkgver}.tar.xz"
+        'navkeys.patch')
+[...]
+build() {
+  cd ex-${pkgver}
+  make
+}
+[...]
+========
+*/
+
+// Static PKGBUILD reader, used as a fallback when there is no builder to generate the .SRCINFO.
+// Nothing is executed: only top level assignments are evaluated and functions are skipped.
+// Everything that would need bash (command substitution, arithmetic, unknown variables,
+// assignments inside conditionals or loops...) is recorded as unevaluated instead.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PkgBuildError {
+    #[error("Invalid data: {0}")]
+    InvalidData(String),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] io::Error),
+}
+
+// .SRCINFO keys, in the makepkg --printsrcinfo order
+const SRCINFO_FIELDS: &[&str] = &[
+    "pkgdesc",
+    "pkgver",
+    "pkgrel",
+    "epoch",
+    "url",
+    "install",
+    "changelog",
+    "arch",
+    "groups",
+    "license",
+    "noextract",
+    "options",
+    "backup",
+    "validpgpkeys",
+];
+
+// .SRCINFO keys that can be suffixed by an architecture (ex: source_x86_64)
+const SRCINFO_ARCH_FIELDS: &[&str] = &[
+    "checkdepends",
+    "makedepends",
+    "depends",
+    "optdepends",
+    "provides",
+    "conflicts",
+    "replaces",
+    "source",
+    "cksums",
+    "md5sums",
+    "sha1sums",
+    "sha224sums",
+    "sha256sums",
+    "sha384sums",
+    "sha512sums",
+    "b2sums",
+];
+
+// Marker for a value that cannot be evaluated without running bash
+struct Unevaluable;
+
+type Expanded = Result<Vec<String>, Unevaluable>;
+
+// pacage generated sections are appended to the PKGBUILD, each one starts with a
+// "# pacage: <name>" line and ends at the next section
+const SECTION_PREFIX: &str = "\n# pacage: ";
+
+/// Replace (or remove when None) the section starting with the marker line
+pub fn replace_section(content: &str, marker: &str, section: Option<&str>) -> String {
+    let mut res = match content.find(&format!("\n{}", marker)) {
+        Some(start) => {
+            let rest = &content[start + 1..];
+            let mut res = format!("{}\n", content[..start].trim_end_matches('\n'));
+            if let Some(end) = rest.find(SECTION_PREFIX) {
+                res.push('\n');
+                res.push_str(rest[end..].trim_start_matches('\n'));
+            }
+            res
+        }
+        None => content.to_string(),
+    };
+    if let Some(section) = section {
+        if !res.ends_with('\n') {
+            res.push('\n');
+        }
+        res.push('\n');
+        res.push_str(section);
+    }
+    res
+}
+
+#[derive(Debug)]
+pub struct PkgBuild {
+    vars: HashMap<String, Vec<String>>,
+    unevaluated: BTreeSet<String>,
+}
+
+impl PkgBuild {
+    pub fn new(mut data: impl BufRead) -> Result<Self, PkgBuildError> {
+        let mut content = String::new();
+        data.read_to_string(&mut content)?;
+        let mut parser = Parser::new(&content);
+        parser.vars.insert(
+            "CARCH".to_string(),
+            vec![std::env::consts::ARCH.to_string()],
+        );
+        parser.parse()?;
+        Ok(Self {
+            vars: parser.vars,
+            unevaluated: parser.unevaluated,
+        })
+    }
+
+    // Scalars are handled as one element arrays, like bash does.
+    // For unevaluated variables, this is only the statically known part.
+    pub fn get(&self, name: &str) -> Option<&[String]> {
+        self.vars.get(name).map(|v| v.as_slice())
+    }
+
+    fn pkgbase(&self) -> Option<&String> {
+        self.get("pkgbase")
+            .or_else(|| self.get("pkgname"))
+            .and_then(|v| v.first())
+    }
+
+    fn is_srcinfo_field(&self, name: &str) -> bool {
+        if ["pkgbase", "pkgname"].contains(&name) || SRCINFO_FIELDS.contains(&name) {
+            return true;
+        }
+        SRCINFO_ARCH_FIELDS.iter().any(|field| {
+            name == *field
+                || name
+                    .strip_prefix(field)
+                    .is_some_and(|arch| arch.starts_with('_'))
+        })
+    }
+
+    /// .SRCINFO fields that could not be statically evaluated
+    pub fn unevaluated(&self) -> Vec<String> {
+        self.unevaluated
+            .iter()
+            .filter(|name| self.is_srcinfo_field(name))
+            .cloned()
+            .collect()
+    }
+
+    /// .SRCINFO lines ("key = value") of everything that could be evaluated
+    pub fn srcinfo_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(pkgbase) = self.pkgbase() {
+            lines.push(format!("pkgbase = {}", pkgbase));
+        }
+        let mut push = |key: &str| {
+            for value in self.get(key).unwrap_or_default() {
+                if !value.is_empty() {
+                    lines.push(format!("\t{} = {}", key, value));
+                }
+            }
+        };
+        for key in SRCINFO_FIELDS {
+            push(key);
+        }
+        for key in SRCINFO_ARCH_FIELDS {
+            push(key);
+        }
+        for arch in self.get("arch").unwrap_or_default() {
+            for key in SRCINFO_ARCH_FIELDS {
+                push(&format!("{}_{}", key, arch));
+            }
+        }
+        lines.push(String::new());
+        for pkgname in self.get("pkgname").unwrap_or_default() {
+            lines.push(format!("pkgname = {}", pkgname));
+        }
+        lines
+    }
+}
+
+struct Parser {
+    s: Vec<char>,
+    i: usize,
+    vars: HashMap<String, Vec<String>>,
+    unevaluated: BTreeSet<String>,
+    // Depth of if/case/for/while blocks, assignments inside them are not evaluated
+    depth: usize,
+    // The current statement is chained with && or ||
+    chained: bool,
+    // Delimiters of the heredocs starting at the next new line
+    heredocs: Vec<(String, bool /* strip tabs */)>,
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// name[index]+=value -> (name, index, append, value)
+fn split_assignment(word: &str) -> Option<(&str, Option<&str>, bool, &str)> {
+    let eq = word.find('=')?;
+    let (left, value) = (&word[..eq], &word[(eq + 1)..]);
+    let (left, append) = match left.strip_suffix('+') {
+        Some(left) => (left, true),
+        None => (left, false),
+    };
+    let (name, index) = match left.find('[') {
+        Some(n) if left.ends_with(']') => (&left[..n], Some(&left[(n + 1)..(left.len() - 1)])),
+        Some(_) => return None,
+        None => (left, None),
+    };
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(is_name_start) || !chars.all(is_name_char) {
+        return None;
+    }
+    Some((name, index, append, value))
+}
+
+// Shell pattern matching, only '*', '?' and '\' are supported
+fn glob_match(pattern: &[char], s: &[char]) -> bool {
+    match pattern.first() {
+        None => s.is_empty(),
+        Some('*') => (0..=s.len()).any(|n| glob_match(&pattern[1..], &s[n..])),
+        Some('?') => !s.is_empty() && glob_match(&pattern[1..], &s[1..]),
+        Some('\\') if pattern.len() > 1 => {
+            s.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &s[1..])
+        }
+        Some(c) => s.first() == Some(c) && glob_match(&pattern[1..], &s[1..]),
+    }
+}
+
+// Index of the char closing the group opened at `open`, quotes are skipped
+fn matching(s: &[char], open: usize, open_char: char, close_char: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open;
+    while i < s.len() {
+        match s[i] {
+            '\\' => i += 1,
+            '\'' => i += s[(i + 1)..].iter().position(|c| *c == '\'')? + 1,
+            '"' => {
+                i += 1;
+                while i < s.len() && s[i] != '"' {
+                    if s[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            c if c == open_char => depth += 1,
+            c if c == close_char => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Bash brace expansion: a{b,c}d -> abd acd
+fn brace_expand(raw: &str) -> Vec<String> {
+    let s: Vec<char> = raw.chars().collect();
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            '\\' => i += 1,
+            '\'' | '"' => match s[(i + 1)..].iter().position(|c| *c == s[i]) {
+                Some(n) => i += n + 1,
+                None => return vec![raw.to_string()],
+            },
+            '$' if s.get(i + 1) == Some(&'{') => match matching(&s, i + 1, '{', '}') {
+                Some(end) => i = end,
+                None => return vec![raw.to_string()],
+            },
+            '{' => {
+                let Some(end) = matching(&s, i, '{', '}') else {
+                    return vec![raw.to_string()];
+                };
+                let mut alternatives = Vec::new();
+                let mut start = i + 1;
+                let mut depth = 0;
+                for n in (i + 1)..end {
+                    match s[n] {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        ',' if depth == 0 => {
+                            alternatives.push(&s[start..n]);
+                            start = n + 1;
+                        }
+                        _ => {}
+                    }
+                }
+                if !alternatives.is_empty() {
+                    alternatives.push(&s[start..end]);
+                    let prefix: String = s[..i].iter().collect();
+                    let suffix: String = s[(end + 1)..].iter().collect();
+                    return alternatives
+                        .into_iter()
+                        .flat_map(|alt| {
+                            let alt: String = alt.iter().collect();
+                            brace_expand(&format!("{}{}{}", prefix, alt, suffix))
+                        })
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![raw.to_string()]
+}
+
+// Words produced by the expansion of a single raw word
+#[derive(Default)]
+struct Fields {
+    fields: Vec<String>,
+    current: String,
+    set: bool,
+}
+
+impl Fields {
+    fn push(&mut self, s: &str) {
+        self.current.push_str(s);
+        self.set = true;
+    }
+
+    // Unquoted expansion, subject to word splitting
+    fn split(&mut self, s: &str) {
+        if s.starts_with(char::is_whitespace) {
+            self.end();
+        }
+        for (n, word) in s.split_whitespace().enumerate() {
+            if n > 0 {
+                self.end();
+            }
+            self.push(word);
+        }
+        if s.ends_with(char::is_whitespace) {
+            self.end();
+        }
+    }
+
+    fn end(&mut self) {
+        if self.set {
+            self.fields.push(std::mem::take(&mut self.current));
+            self.set = false;
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.end();
+        self.fields
+    }
+}
+
+impl Parser {
+    fn new(content: &str) -> Self {
+        Self {
+            s: content.chars().collect(),
+            i: 0,
+            vars: HashMap::new(),
+            unevaluated: BTreeSet::new(),
+            depth: 0,
+            chained: false,
+            heredocs: Vec::new(),
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.s.get(self.i + offset).copied()
+    }
+
+    fn at_word_start(&self) -> bool {
+        self.i == 0
+            || matches!(
+                self.s[self.i - 1],
+                ' ' | '\t' | '\n' | ';' | '(' | ')' | '&' | '|'
+            )
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek(0).is_some_and(|c| c != '\n') {
+            self.i += 1;
+        }
+    }
+
+    // Consume a new line, and the heredocs bodies starting after it
+    fn newline(&mut self) {
+        self.i += 1;
+        for (delimiter, strip_tabs) in std::mem::take(&mut self.heredocs) {
+            while self.i < self.s.len() {
+                let start = self.i;
+                self.skip_comment();
+                let line: String = self.s[start..self.i].iter().collect();
+                self.i += 1;
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line.as_str()
+                };
+                if line == delimiter {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn heredoc(&mut self) {
+        self.i += 2;
+        if self.peek(0) == Some('<') {
+            // Here string
+            self.i += 1;
+            return;
+        }
+        let strip_tabs = self.peek(0) == Some('-');
+        if strip_tabs {
+            self.i += 1;
+        }
+        while matches!(self.peek(0), Some(' ') | Some('\t')) {
+            self.i += 1;
+        }
+        let delimiter: String = self
+            .word()
+            .chars()
+            .filter(|c| !matches!(c, '\'' | '"' | '\\'))
+            .collect();
+        if !delimiter.is_empty() {
+            self.heredocs.push((delimiter, strip_tabs));
+        }
+    }
+
+    // Consume a backslash and the escaped char, a trailing backslash stops at the end
+    fn skip_escape(&mut self) {
+        self.i = (self.i + 2).min(self.s.len());
+    }
+
+    // Consume one char, or a whole quoted string/expansion
+    fn skip_char(&mut self) {
+        match self.peek(0) {
+            Some('\\') => self.skip_escape(),
+            Some('\'') => {
+                self.i += 1;
+                while let Some(c) = self.peek(0) {
+                    self.i += 1;
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            Some('"') => {
+                self.i += 1;
+                while let Some(c) = self.peek(0) {
+                    match c {
+                        '"' => {
+                            self.i += 1;
+                            break;
+                        }
+                        '\\' => self.skip_escape(),
+                        '$' | '`' => self.skip_char(),
+                        _ => self.i += 1,
+                    }
+                }
+            }
+            Some('`') => {
+                self.i += 1;
+                while let Some(c) = self.peek(0) {
+                    if c == '\\' {
+                        self.skip_escape();
+                        continue;
+                    }
+                    self.i += 1;
+                    if c == '`' {
+                        break;
+                    }
+                }
+            }
+            Some('$') if self.peek(1) == Some('(') => {
+                self.i += 1;
+                self.skip_group('(', ')');
+            }
+            Some('$') if self.peek(1) == Some('{') => {
+                self.i += 1;
+                self.skip_group('{', '}');
+            }
+            Some(_) => self.i += 1,
+            None => {}
+        }
+    }
+
+    // Skip a balanced group, starting on its opening char
+    fn skip_group(&mut self, open: char, close: char) {
+        let mut depth = 0;
+        while let Some(c) = self.peek(0) {
+            match c {
+                c if c == open => {
+                    depth += 1;
+                    self.i += 1;
+                }
+                c if c == close => {
+                    self.i += 1;
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                '\n' => self.newline(),
+                '#' if self.at_word_start() => self.skip_comment(),
+                '<' if self.peek(1) == Some('<') => self.heredoc(),
+                _ => self.skip_char(),
+            }
+        }
+    }
+
+    // Skip the rest of a command, up to the end of the statement
+    fn skip_command(&mut self) {
+        while let Some(c) = self.peek(0) {
+            match c {
+                '\n' | ';' | '&' | '|' => break,
+                '(' => self.skip_group('(', ')'),
+                '<' if self.peek(1) == Some('<') => self.heredoc(),
+                '#' if self.at_word_start() => self.skip_comment(),
+                '\\' if self.peek(1) == Some('\n') => self.i += 2,
+                _ => self.skip_char(),
+            }
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        loop {
+            match self.peek(0) {
+                Some(' ') | Some('\t') => self.i += 1,
+                Some('\\') if self.peek(1) == Some('\n') => self.i += 2,
+                _ => break,
+            }
+        }
+    }
+
+    // Skip everything in between two statements
+    fn skip_blank(&mut self) {
+        let mut operator = false;
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' => self.i += 1,
+                '\\' if self.peek(1) == Some('\n') => self.i += 2,
+                '&' | '|' => {
+                    operator = true;
+                    self.chained = true;
+                    self.i += 1;
+                }
+                ';' => {
+                    operator = false;
+                    self.chained = false;
+                    self.i += 1;
+                }
+                '\n' => {
+                    if !operator {
+                        self.chained = false;
+                    }
+                    self.newline();
+                }
+                '#' => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    // Raw shell word, quotes and expansions are kept as is
+    fn word(&mut self) -> String {
+        let start = self.i;
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' | '\n' | ';' | '&' | '|' | '(' | ')' | '<' | '>' => break,
+                _ => self.skip_char(),
+            }
+        }
+        self.s[start..self.i].iter().collect()
+    }
+
+    fn parse(&mut self) -> Result<(), PkgBuildError> {
+        loop {
+            self.skip_blank();
+            if self.peek(0).is_none() {
+                return Ok(());
+            }
+            self.statement()?;
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), PkgBuildError> {
+        let conditional = self.depth > 0 || self.chained;
+        let word = self.word();
+        if word.is_empty() {
+            match self.peek(0) {
+                Some('(') => self.skip_group('(', ')'),
+                Some('<') | Some('>') => self.skip_command(),
+                _ => self.i += 1,
+            }
+            return Ok(());
+        }
+        if self.peek(0) == Some(')') {
+            // case pattern
+            self.i += 1;
+            return Ok(());
+        }
+        match word.as_str() {
+            "if" | "while" | "until" | "for" | "select" | "case" => {
+                self.depth += 1;
+                self.skip_command();
+            }
+            "fi" | "done" | "esac" => self.depth = self.depth.saturating_sub(1),
+            "then" | "else" | "do" | "{" | "}" | "!" => {}
+            "elif" => self.skip_command(),
+            "function" => {
+                self.skip_spaces();
+                self.word();
+                self.function_body();
+            }
+            "declare" | "typeset" | "local" | "export" | "readonly" => loop {
+                self.skip_spaces();
+                if self.peek(0) != Some('-') {
+                    break;
+                }
+                self.word();
+            },
+            _ => {
+                if let Some((name, index, append, value)) = split_assignment(&word) {
+                    let (name, index, value) = (
+                        name.to_string(),
+                        index.map(|i| i.to_string()),
+                        value.to_string(),
+                    );
+                    self.assign(name, index, append, value, conditional)?;
+                } else {
+                    self.skip_spaces();
+                    if self.peek(0) == Some('(') && self.peek(1) == Some(')') {
+                        self.function_body();
+                    } else {
+                        self.skip_command();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn function_body(&mut self) {
+        self.skip_spaces();
+        if self.peek(0) == Some('(') && self.peek(1) == Some(')') {
+            self.i += 2;
+        }
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' => self.i += 1,
+                '\n' => self.newline(),
+                _ => break,
+            }
+        }
+        match self.peek(0) {
+            Some('{') => self.skip_group('{', '}'),
+            Some('(') => self.skip_group('(', ')'),
+            _ => self.skip_command(),
+        }
+    }
+
+    fn assign(
+        &mut self,
+        name: String,
+        index: Option<String>,
+        append: bool,
+        value: String,
+        conditional: bool,
+    ) -> Result<(), PkgBuildError> {
+        let is_array = value.is_empty() && self.peek(0) == Some('(');
+        let values = if is_array {
+            self.array()?
+        } else {
+            self.expand(&value, false)
+                .map(|fields| vec![fields.join(" ")])
+        };
+        // The statically known value is kept for conditional assignments
+        if conditional || index.is_some() {
+            self.unevaluated.insert(name);
+            return Ok(());
+        }
+        let Ok(values) = values else {
+            self.vars.remove(&name);
+            self.unevaluated.insert(name);
+            return Ok(());
+        };
+        match self.vars.get_mut(&name) {
+            Some(old) if append && is_array => old.extend(values),
+            Some(old) if append => match old.first_mut() {
+                Some(first) => first.push_str(&values.concat()),
+                None => *old = values,
+            },
+            _ => {
+                self.vars.insert(name.clone(), values);
+                if !append {
+                    self.unevaluated.remove(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn array(&mut self) -> Result<Expanded, PkgBuildError> {
+        self.i += 1;
+        let mut items = Ok(Vec::new());
+        loop {
+            match self.peek(0) {
+                None => return Err(PkgBuildError::InvalidData("Unterminated array".to_string())),
+                Some(' ') | Some('\t') => self.i += 1,
+                Some('\\') if self.peek(1) == Some('\n') => self.i += 2,
+                Some('\n') => self.newline(),
+                Some('#') if self.at_word_start() => self.skip_comment(),
+                Some(')') => {
+                    self.i += 1;
+                    return Ok(items);
+                }
+                Some(_) => {
+                    let word = self.word();
+                    if word.is_empty() {
+                        self.skip_char();
+                        items = Err(Unevaluable);
+                        continue;
+                    }
+                    match (&mut items, self.expand(&word, true)) {
+                        (Ok(items), Ok(fields)) => items.extend(fields),
+                        _ => items = Err(Unevaluable),
+                    }
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Vec<String>> {
+        if self.unevaluated.contains(name) {
+            return None;
+        }
+        self.vars.get(name)
+    }
+
+    // Expand a raw word, brace expansion and word splitting only happen in arrays
+    fn expand(&self, raw: &str, split: bool) -> Expanded {
+        let words = if split {
+            brace_expand(raw)
+        } else {
+            vec![raw.to_string()]
+        };
+        let mut res = Vec::new();
+        for word in words {
+            let s: Vec<char> = word.chars().collect();
+            let mut fields = Fields::default();
+            let mut i = 0;
+            while i < s.len() {
+                match s[i] {
+                    '\\' => {
+                        if let Some(c) = s.get(i + 1) {
+                            fields.push(&c.to_string());
+                        }
+                        i += 2;
+                    }
+                    '\'' => {
+                        let end = s[(i + 1)..]
+                            .iter()
+                            .position(|c| *c == '\'')
+                            .ok_or(Unevaluable)?;
+                        let quoted: String = s[(i + 1)..(i + 1 + end)].iter().collect();
+                        fields.push(&quoted);
+                        i += end + 2;
+                    }
+                    '"' => {
+                        fields.push("");
+                        i += 1;
+                        while i < s.len() && s[i] != '"' {
+                            match s[i] {
+                                '\\' if s
+                                    .get(i + 1)
+                                    .is_some_and(|c| matches!(c, '$' | '`' | '"' | '\\')) =>
+                                {
+                                    fields.push(&s[i + 1].to_string());
+                                    i += 2;
+                                }
+                                '$' => i = self.dollar(&s, i, true, &mut fields)?,
+                                '`' => return Err(Unevaluable),
+                                c => {
+                                    fields.push(&c.to_string());
+                                    i += 1;
+                                }
+                            }
+                        }
+                        i += 1;
+                    }
+                    '$' => i = self.dollar(&s, i, !split, &mut fields)?,
+                    '`' => return Err(Unevaluable),
+                    c => {
+                        fields.push(&c.to_string());
+                        i += 1;
+                    }
+                }
+            }
+            res.extend(fields.finish());
+        }
+        Ok(res)
+    }
+
+    // Expand the '$' at s[i], return the index after the expansion
+    fn dollar(
+        &self,
+        s: &[char],
+        i: usize,
+        quoted: bool,
+        fields: &mut Fields,
+    ) -> Result<usize, Unevaluable> {
+        let (values, array, end) = match s.get(i + 1) {
+            Some('{') => {
+                let end = matching(s, i + 1, '{', '}').ok_or(Unevaluable)?;
+                let body: String = s[(i + 2)..end].iter().collect();
+                let (values, array) = self.parameter(&body)?;
+                (values, array, end + 1)
+            }
+            Some(c) if is_name_start(*c) => {
+                let len = s[(i + 1)..]
+                    .iter()
+                    .position(|c| !is_name_char(*c))
+                    .unwrap_or(s.len() - i - 1);
+                let name: String = s[(i + 1)..(i + 1 + len)].iter().collect();
+                let value = self.lookup(&name).ok_or(Unevaluable)?;
+                (
+                    vec![value.first().cloned().unwrap_or_default()],
+                    false,
+                    i + 1 + len,
+                )
+            }
+            // Command substitution, arithmetic, positional and special parameters
+            Some(c) if c.is_ascii_digit() || "(@*#?$!-".contains(*c) => Err(Unevaluable)?,
+            _ => {
+                fields.push("$");
+                return Ok(i + 1);
+            }
+        };
+        match (quoted, array) {
+            (true, true) => {
+                for (n, value) in values.iter().enumerate() {
+                    if n > 0 {
+                        fields.end();
+                    }
+                    fields.push(value);
+                }
+            }
+            (true, false) => fields.push(&values.join(" ")),
+            (false, _) => {
+                for value in values {
+                    fields.split(&value);
+                }
+            }
+        }
+        Ok(end)
+    }
+
+    // ${...} content -> (values, expanded as an array)
+    fn parameter(&self, body: &str) -> Result<(Vec<String>, bool), Unevaluable> {
+        if let Some(name) = body.strip_prefix('#') {
+            let (name, index) = match name.find('[') {
+                Some(n) => (&name[..n], Some(&name[n..])),
+                None => (name, None),
+            };
+            let value = self.lookup(name).ok_or(Unevaluable)?;
+            let len = match index {
+                Some("[@]") | Some("[*]") => value.len(),
+                None => value.first().map(|v| v.chars().count()).unwrap_or(0),
+                Some(_) => Err(Unevaluable)?,
+            };
+            return Ok((vec![len.to_string()], false));
+        }
+        let len = body
+            .chars()
+            .position(|c| !is_name_char(c))
+            .unwrap_or(body.len());
+        let name = &body[..len];
+        if name.is_empty() || !name.starts_with(is_name_start) {
+            Err(Unevaluable)?
+        }
+        let mut op = &body[len..];
+        let mut array = false;
+        let mut index = None;
+        if op.starts_with('[') {
+            let end = op.find(']').ok_or(Unevaluable)?;
+            match &op[1..end] {
+                "@" => array = true,
+                "*" => {}
+                n => index = Some(n.parse::<usize>().map_err(|_| Unevaluable)?),
+            }
+            op = &op[(end + 1)..];
+        }
+        let value = self.lookup(name).map(|value| match (index, array) {
+            (Some(n), _) => value.get(n).cloned().into_iter().collect(),
+            (None, true) => value.clone(),
+            (None, false) if body[len..].starts_with("[*]") => vec![value.join(" ")],
+            (None, false) => value.first().cloned().into_iter().collect::<Vec<_>>(),
+        });
+        let is_empty = |v: &Vec<String>| v.iter().all(|v| v.is_empty());
+        for (prefix, colon) in [(":-", true), ("-", false)] {
+            if let Some(default) = op.strip_prefix(prefix) {
+                return match value {
+                    Some(value) if !(colon && is_empty(&value)) => Ok((value, array)),
+                    _ => Ok((self.expand(default, false)?, false)),
+                };
+            }
+        }
+        for (prefix, colon) in [(":+", true), ("+", false)] {
+            if let Some(alternative) = op.strip_prefix(prefix) {
+                return match value {
+                    Some(value) if !(colon && is_empty(&value)) => {
+                        Ok((self.expand(alternative, false)?, false))
+                    }
+                    _ => Ok((vec![], false)),
+                };
+            }
+        }
+        let value = value.ok_or(Unevaluable)?;
+        let values = value
+            .iter()
+            .map(|v| self.operation(v, op))
+            .collect::<Result<Vec<String>, Unevaluable>>()?;
+        Ok((values, array))
+    }
+
+    fn pattern(&self, raw: &str) -> Result<Vec<char>, Unevaluable> {
+        if raw.contains('[') {
+            Err(Unevaluable)?
+        }
+        Ok(self.expand(raw, false)?.join(" ").chars().collect())
+    }
+
+    fn operation(&self, value: &str, op: &str) -> Result<String, Unevaluable> {
+        let chars: Vec<char> = value.chars().collect();
+        let res = if op.is_empty() {
+            value.to_string()
+        } else if let Some(pattern) = op.strip_prefix("%%") {
+            let pattern = self.pattern(pattern)?;
+            (0..=chars.len())
+                .find(|n| glob_match(&pattern, &chars[*n..]))
+                .map(|n| chars[..n].iter().collect())
+                .unwrap_or_else(|| value.to_string())
+        } else if let Some(pattern) = op.strip_prefix('%') {
+            let pattern = self.pattern(pattern)?;
+            (0..=chars.len())
+                .rev()
+                .find(|n| glob_match(&pattern, &chars[*n..]))
+                .map(|n| chars[..n].iter().collect())
+                .unwrap_or_else(|| value.to_string())
+        } else if let Some(pattern) = op.strip_prefix("##") {
+            let pattern = self.pattern(pattern)?;
+            (0..=chars.len())
+                .rev()
+                .find(|n| glob_match(&pattern, &chars[..*n]))
+                .map(|n| chars[n..].iter().collect())
+                .unwrap_or_else(|| value.to_string())
+        } else if let Some(pattern) = op.strip_prefix('#') {
+            let pattern = self.pattern(pattern)?;
+            (0..=chars.len())
+                .find(|n| glob_match(&pattern, &chars[..*n]))
+                .map(|n| chars[n..].iter().collect())
+                .unwrap_or_else(|| value.to_string())
+        } else if let Some(substitution) = op.strip_prefix('/') {
+            let (all, substitution) = match substitution.strip_prefix('/') {
+                Some(s) => (true, s),
+                None => (false, substitution),
+            };
+            let (pattern, replacement) = match substitution.find('/') {
+                Some(n) => (&substitution[..n], &substitution[(n + 1)..]),
+                None => (substitution, ""),
+            };
+            let replacement = self.expand(replacement, false)?.join(" ");
+            if let Some(pattern) = pattern.strip_prefix('#') {
+                let pattern: String = self.pattern(pattern)?.iter().collect();
+                match value.strip_prefix(&pattern) {
+                    Some(rest) => format!("{}{}", replacement, rest),
+                    None => value.to_string(),
+                }
+            } else if let Some(pattern) = pattern.strip_prefix('%') {
+                let pattern: String = self.pattern(pattern)?.iter().collect();
+                match value.strip_suffix(&pattern) {
+                    Some(rest) => format!("{}{}", rest, replacement),
+                    None => value.to_string(),
+                }
+            } else {
+                let pattern = self.pattern(pattern)?;
+                if pattern.iter().any(|c| matches!(c, '*' | '?' | '\\')) {
+                    Err(Unevaluable)?
+                }
+                let pattern: String = pattern.iter().collect();
+                if pattern.is_empty() {
+                    value.to_string()
+                } else if all {
+                    value.replace(&pattern, &replacement)
+                } else {
+                    value.replacen(&pattern, &replacement, 1)
+                }
+            }
+        } else if op == "^^" {
+            value.to_uppercase()
+        } else if op == ",," {
+            value.to_lowercase()
+        } else if let Some(range) = op.strip_prefix(':') {
+            let (offset, length) = match range.find(':') {
+                Some(n) => (&range[..n], Some(&range[(n + 1)..])),
+                None => (range, None),
+            };
+            let offset = offset.trim().parse::<usize>().map_err(|_| Unevaluable)?;
+            let rest = chars.iter().skip(offset);
+            match length {
+                Some(length) => {
+                    let length = length.trim().parse::<usize>().map_err(|_| Unevaluable)?;
+                    rest.take(length).collect()
+                }
+                None => rest.collect(),
+            }
+        } else {
+            Err(Unevaluable)?
+        };
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::format::SrcInfo;
+
+    fn parse(content: &str) -> PkgBuild {
+        PkgBuild::new(content.as_bytes()).unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn expansions() {
+        let pkgbuild = parse(
+            r#"
+_name=requests # comment
+pkgname=python-$_name
+pkgver=6.9.7.arch1
+_srctag=v${pkgver%.*}-${pkgver##*.}
+_major=${pkgver%%.*}
+_under=${pkgver//./_}
+_upper="${_name^^}"
+_default=${_unset:-fallback}
+_quoted='$pkgver "literal"'
+arch=(x86_64 'aarch64')
+depends=(glibc "${arch[@]}" lib{a,b}.so)
+depends+=(
+  # comment in array
+  zlib
+)
+_unknown=$(git describe)
+_words=($_unknown foo)
+build() {
+  local depends=(nope)
+  cat <<EOF
+it's } not closed
+EOF
+}
+if [[ $CARCH == x86_64 ]]; then
+  makedepends=(nasm)
+fi
+[[ -n $foo ]] && checkdepends=(python-pytest)
+"#,
+        );
+        let get = |name: &str| pkgbuild.get(name).map(|v| v.to_vec());
+        assert_eq!(get("pkgname"), Some(strings(&["python-requests"])));
+        assert_eq!(get("_srctag"), Some(strings(&["v6.9.7-arch1"])));
+        assert_eq!(get("_major"), Some(strings(&["6"])));
+        assert_eq!(get("_under"), Some(strings(&["6_9_7_arch1"])));
+        assert_eq!(get("_upper"), Some(strings(&["REQUESTS"])));
+        assert_eq!(get("_default"), Some(strings(&["fallback"])));
+        assert_eq!(get("_quoted"), Some(strings(&["$pkgver \"literal\""])));
+        assert_eq!(
+            get("depends"),
+            Some(strings(&[
+                "glibc", "x86_64", "aarch64", "liba.so", "libb.so", "zlib"
+            ]))
+        );
+        assert_eq!(get("_unknown"), None);
+        assert_eq!(get("_words"), None);
+        assert_eq!(get("makedepends"), None);
+        assert_eq!(get("checkdepends"), None);
+        assert_eq!(
+            pkgbuild.unevaluated(),
+            strings(&["checkdepends", "makedepends"])
+        );
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        // Untrusted input, must not panic
+        for content in ["pkgname=foo\\", "a=\"\\", "a=`\\", "a=$(b \\"] {
+            PkgBuild::new(content.as_bytes()).ok();
+        }
+        let pkgbuild = parse("pkgver=1\npkgname=foo\\");
+        assert_eq!(
+            pkgbuild.get("pkgver").map(|v| v.to_vec()),
+            Some(strings(&["1"]))
+        );
+    }
+
+    fn fixture(path: &str) -> PathBuf {
+        PathBuf::from("../resources/tests").join(path)
+    }
+
+    fn static_srcinfo(dir: &PathBuf) -> SrcInfo {
+        let pkgbuild =
+            PkgBuild::new(BufReader::new(File::open(dir.join("PKGBUILD")).unwrap())).unwrap();
+        SrcInfo::from_pkgbuild(&pkgbuild).unwrap()
+    }
+
+    #[test]
+    fn sections() {
+        let pkgbuild = "pkgname=foo\npkgrel=2\n";
+        let a = replace_section(pkgbuild, "# pacage: a", Some("# pacage: a\nA=1\n"));
+        let ab = replace_section(&a, "# pacage: b", Some("# pacage: b\nB=1\n"));
+        assert_eq!(
+            ab,
+            "pkgname=foo\npkgrel=2\n\n# pacage: a\nA=1\n\n# pacage: b\nB=1\n"
+        );
+        // Only the replaced section moves
+        let ba = replace_section(&ab, "# pacage: a", Some("# pacage: a\nA=2\n"));
+        assert_eq!(
+            ba,
+            "pkgname=foo\npkgrel=2\n\n# pacage: b\nB=1\n\n# pacage: a\nA=2\n"
+        );
+        let b = replace_section(&ba, "# pacage: a", None);
+        assert_eq!(b, "pkgname=foo\npkgrel=2\n\n# pacage: b\nB=1\n");
+        assert_eq!(replace_section(&b, "# pacage: b", None), pkgbuild);
+        assert_eq!(replace_section(pkgbuild, "# pacage: a", None), pkgbuild);
+    }
+
+    #[test]
+    fn same_as_srcinfo() {
+        for pkg in ["fake_pkg1", "fake_pkg2"] {
+            let dir = fixture(pkg);
+            let file = File::open(dir.join(".SRCINFO")).unwrap();
+            let expected =
+                SrcInfo::parse(BufReader::new(file).lines().map(|l| l.unwrap())).unwrap();
+            let res = static_srcinfo(&dir);
+            assert!(res.unevaluated.is_empty(), "{}: {:?}", pkg, res.unevaluated);
+            assert_eq!(res.name, expected.name);
+            assert_eq!(res.get_version(), expected.get_version());
+            assert_eq!(res.pkgrel, expected.pkgrel);
+            assert_eq!(res.epoch, expected.epoch);
+            assert_eq!(res.arch, expected.arch);
+            assert_eq!(res.deps, expected.deps);
+            assert_eq!(res.src, expected.src);
+        }
+    }
+
+    #[test]
+    fn arch_corpus() {
+        let carch = std::env::consts::ARCH;
+        for (pkg, name, version, deps, unevaluated) in [
+            (
+                "bash",
+                "bash",
+                "5.2.026-5",
+                vec!["readline", "libreadline.so", "glibc", "ncurses"],
+                vec!["source"],
+            ),
+            ("vi", "vi", "1:070224-6", vec!["ncurses"], vec![]),
+            (
+                "zstd",
+                "zstd",
+                "1.5.6-1",
+                vec!["glibc", "gcc-libs", "zlib", "xz", "lz4"],
+                vec![],
+            ),
+            (
+                "linux",
+                "linux",
+                "6.9.7.arch1-1",
+                // Only in the package functions
+                vec![],
+                vec![],
+            ),
+            (
+                "python-requests",
+                "python-requests",
+                "2.32.3-1",
+                vec!["python-charset-normalizer", "python-idna", "python-urllib3"],
+                vec![],
+            ),
+        ] {
+            let res = static_srcinfo(&fixture("pkgbuilds").join(pkg));
+            assert_eq!(res.name, name, "{}", pkg);
+            assert_eq!(res.get_version().to_string(), version, "{}", pkg);
+            assert_eq!(res.deps, strings(&deps), "{}", pkg);
+            assert!(res.src, "{}", pkg);
+            assert_eq!(res.unevaluated, strings(&unevaluated), "{}", pkg);
+        }
+        let file = File::open(fixture("pkgbuilds").join("linux").join("PKGBUILD")).unwrap();
+        let linux = PkgBuild::new(BufReader::new(file)).unwrap();
+        assert_eq!(
+            linux.get("source").unwrap(),
+            strings(&[
+                "https://cdn.kernel.org/pub/linux/kernel/v6.x/linux-6.9.7.tar.xz",
+                "https://cdn.kernel.org/pub/linux/kernel/v6.x/linux-6.9.7.tar.sign",
+                "https://github.com/archlinux/linux/releases/download/v6.9.7-arch1/linux-v6.9.7-arch1.patch.zst",
+                "https://github.com/archlinux/linux/releases/download/v6.9.7-arch1/linux-v6.9.7-arch1.patch.zst.sig",
+                "config",
+            ])
+        );
+        assert_eq!(
+            linux.get("pkgname").unwrap(),
+            strings(&["linux", "linux-headers", "linux-docs"])
+        );
+        let res = static_srcinfo(&fixture("pkgbuilds").join("visual-studio-code-bin"));
+        assert_eq!(res.name, "visual-studio-code-bin");
+        assert!(res.unevaluated.is_empty(), "{:?}", res.unevaluated);
+        assert_eq!(res.src, ["x86_64", "aarch64", "armv7h"].contains(&carch));
+    }
+}
@@ -208,6 +208,9 @@ impl PkgInfo {
             optdepends: self.optdepends.clone(),
             makedepends: self.makedepends.clone(),
             checkdepends: self.checkdepends.clone(),
+            use_flags: Vec::new(),
+            fingerprint: None,
+            hold: false,
         }
     }
 }
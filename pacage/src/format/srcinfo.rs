@@ -1,12 +1,10 @@
-use super::ParsingError;
-use crate::cmd::ExecError;
-use crate::cmd::{command, CmdError, NOENV};
+use super::{ParsingError, PkgBuild};
 use crate::conf::PkgsDir;
 use crate::utils::version::Version;
 use log::warn;
 use std::borrow::Borrow;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, ErrorKind};
 use std::io::{BufRead, BufReader};
 use thiserror::Error;
 
@@ -52,22 +50,16 @@ pkgname = bash
 ========
 */
 
-// TODO: Caution, from arch wiki:
+// From arch wiki:
 // The following fields may, additionally, specify multiple architectures as shown below:
 // source_x86_64 = https://foo.bar/file.tar.gz
 // source_i686 = https://foo.bar/file_i686_patch.tar.gz
-//     source
-//     depends, checkdepends, makedepends, optdepends
-//     provides, conflicts, replaces
-//     md5sums, sha1sums, sha224sums, sha256sums, sha384sums, sha512sums
+// Only the depends and source of the host architecture are used.
 
 #[derive(Debug, Error)]
 pub enum SrcInfoError {
-    #[error("System command error: {0}")]
-    Cmd(#[from] CmdError),
-
-    #[error("Wrong exit status: {0}")]
-    ExecError(ExecError),
+    #[error("Missing PKGBUILD")]
+    Missing,
 
     #[error("Invalid data: {0}")]
     InvalidData(String),
@@ -76,15 +68,21 @@ pub enum SrcInfoError {
     Io(io::Error),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SrcInfo {
     pub name: String,
+    // Packages of a split package, the pkgbase is the only one when empty
+    pub pkgnames: Vec<String>,
     pub pkgver: String, // Cannot contain "-"
     pub pkgrel: Option<String>,
     pub epoch: Option<u32>,
     pub deps: Vec<String>,
     pub src: bool,
     pub arch: String,
+    // Fields that could not be statically read from the PKGBUILD
+    pub unevaluated: Vec<String>,
+    // Build inputs fingerprint, set before the build
+    pub fingerprint: Option<String>,
     _version: Version,
 }
 
@@ -102,19 +100,22 @@ impl std::hash::Hash for SrcInfo {
 impl std::cmp::Eq for SrcInfo {}
 
 impl SrcInfo {
-    fn parse<'a, I>(lines: I) -> Result<Self, ParsingError>
+    pub(super) fn parse<I>(lines: I) -> Result<Self, ParsingError>
     where
         I: IntoIterator,
         I::Item: Borrow<str>,
     {
         // TODO: handle multiple pkgname
         let mut name = None;
+        let mut pkgnames = Vec::new();
         let mut version = None;
         let mut deps = Vec::new();
         let mut src = false;
         let mut epoch = None;
         let mut release = None;
         let mut arch = None;
+        let depends_arch = format!("depends_{}", std::env::consts::ARCH);
+        let source_arch = format!("source_{}", std::env::consts::ARCH);
         for line in lines {
             let line = line.borrow();
             if let Some(n) = line.find('=') {
@@ -125,6 +126,7 @@ impl SrcInfo {
                 let v = line[(n + 1)..].trim();
                 match key {
                     "pkgbase" => name = Some(v.to_string()),
+                    "pkgname" => pkgnames.push(v.to_string()),
                     "pkgver" => version = Some(v.to_string()),
                     "pkgrel" => release = Some(v.to_string()),
                     "arch" => {
@@ -142,6 +144,8 @@ impl SrcInfo {
                     },
                     "depends" => deps.push(v.to_string()),
                     "source" => src = true,
+                    k if k == depends_arch => deps.push(v.to_string()),
+                    k if k == source_arch => src = true,
                     _ => {}
                 }
             }
@@ -156,12 +160,15 @@ impl SrcInfo {
                 return Ok(Self {
                     _version: Version::new(&version, release.as_deref(), epoch),
                     name: name.to_string(),
+                    pkgnames,
                     pkgver: version,
                     pkgrel: release,
                     arch: arch.to_string(),
                     epoch,
                     deps,
                     src,
+                    unevaluated: Vec::new(),
+                    fingerprint: None,
                 });
             }
             _ => Err(SrcInfoError::InvalidData(format!(
@@ -186,37 +193,54 @@ impl SrcInfo {
     //     return Ok(false);
     // }
 
-    pub fn new(
-        pkgs_dir: &PkgsDir,
-        pkg_name: &str,
-        force_recreate: bool,
-    ) -> Result<Self, ParsingError> {
+    // The .SRCINFO is never generated on the host, sourcing a PKGBUILD run arbitrary bash.
+    // Use Builder::srcinfo to (re)generate it inside the builder container.
+    // Without .SRCINFO the PKGBUILD is statically read, see PkgBuild.
+    pub fn new(pkgs_dir: &PkgsDir, pkg_name: &str) -> Result<Self, ParsingError> {
         let path = pkgs_dir.path().join(pkg_name).join(".SRCINFO");
-        // let build = Self::can_build(conf, pkg_name)?;
-        if force_recreate || !path.exists() {
-            // println!("[{}] .srcinfo doesnt exist, creating it...", pkg_name);
-            let pkgs_dir = pkgs_dir.pkg(pkg_name);
-            let (status, out, _) = command(&["makepkg", "--printsrcinfo"], &pkgs_dir, NOENV)
-                .map_err(|e| SrcInfoError::ExecError(e))?;
-            if !status.success() {
-                return Err(SrcInfoError::Cmd(CmdError::from_output(out)).into());
-            }
-            let content = out.join("\n");
-            if let Ok(mut f) = fs::File::create(path) {
-                f.write_all(content.as_bytes()).ok();
+        let file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Self::from_pkgbuild_file(pkgs_dir, pkg_name)
             }
-            Self::parse(content.lines())
-        } else {
-            // println!("[{}] .srcinfo exist, using it...", pkg_name);
-            let file = fs::File::open(path).map_err(|e| SrcInfoError::Io(e))?;
-            Self::parse(BufReader::new(file).lines().filter_map(|l| match l {
-                Ok(l) => Some(l),
-                Err(_) => None,
-            }))
+            Err(e) => Err(SrcInfoError::Io(e))?,
+        };
+        Self::parse(BufReader::new(file).lines().filter_map(|l| match l {
+            Ok(l) => Some(l),
+            Err(_) => None,
+        }))
+    }
+
+    fn from_pkgbuild_file(pkgs_dir: &PkgsDir, pkg_name: &str) -> Result<Self, ParsingError> {
+        let path = pkgs_dir.path().join(pkg_name).join("PKGBUILD");
+        let file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(SrcInfoError::Missing)?,
+            Err(e) => Err(SrcInfoError::Io(e))?,
+        };
+        let srcinfo = Self::from_pkgbuild(&PkgBuild::new(BufReader::new(file))?)?;
+        if !srcinfo.unevaluated.is_empty() {
+            warn!(
+                "[{}] No .SRCINFO, could not statically evaluate: {}",
+                pkg_name,
+                srcinfo.unevaluated.join(", ")
+            );
         }
+        Ok(srcinfo)
+    }
+
+    pub fn from_pkgbuild(pkgbuild: &PkgBuild) -> Result<Self, ParsingError> {
+        let mut srcinfo = Self::parse(pkgbuild.srcinfo_lines())?;
+        srcinfo.unevaluated = pkgbuild.unevaluated();
+        Ok(srcinfo)
     }
 
     pub fn get_version(&self) -> &Version {
         &self._version
     }
+
+    pub fn set_pkgrel(&mut self, pkgrel: &str) {
+        self.pkgrel = Some(pkgrel.to_string());
+        self._version = Version::new(&self.pkgver, Some(pkgrel), self.epoch);
+    }
 }
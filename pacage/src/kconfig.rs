@@ -0,0 +1,212 @@
+/*
+Kernel config fragment, <conf_dir>/kconfig/<pkg>.config:
+
+==== kconfig/linux.config ====
+CONFIG_HZ_1000=y
+CONFIG_HZ=1000
+# CONFIG_HZ_300 is not set
+========
+
+It is merged into the `config` source of the PKGBUILD before prepare() runs, like
+scripts/kconfig/merge_config.sh: every symbol of the fragment replaces the upstream one.
+*/
+
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::conf::Conf;
+
+// Upstream config, kept to merge again from it
+const ORIG_EXTENSION: &str = "pacage.orig";
+
+// "CONFIG_FOO=y" -> ("CONFIG_FOO", Some("y")), "# CONFIG_FOO is not set" -> ("CONFIG_FOO", None)
+fn parse_line(line: &str) -> Option<(&str, Option<&str>)> {
+    let line = line.trim();
+    if let Some(symbol) = line
+        .strip_prefix("# ")
+        .and_then(|l| l.strip_suffix(" is not set"))
+    {
+        return symbol.starts_with("CONFIG_").then_some((symbol, None));
+    }
+    let (symbol, value) = line.split_once('=')?;
+    symbol
+        .starts_with("CONFIG_")
+        .then_some((symbol, Some(value)))
+}
+
+fn entries(config: &str) -> BTreeMap<&str, Option<&str>> {
+    config.lines().filter_map(parse_line).collect()
+}
+
+pub fn symbols(config: &str) -> BTreeSet<&str> {
+    entries(config).into_keys().collect()
+}
+
+// Log the upstream values overridden by the fragment, like merge_config.sh
+fn check_fragment(name: &str, upstream: &str, fragment: &str) {
+    let upstream = entries(upstream);
+    for (symbol, value) in entries(fragment) {
+        match upstream.get(symbol) {
+            Some(previous) if *previous != value => info!(
+                "[{}] Value of {} is redefined by fragment: {} -> {}",
+                name,
+                symbol,
+                previous.unwrap_or("is not set"),
+                value.unwrap_or("is not set")
+            ),
+            Some(_) => {}
+            None => warn!("[{}] {} is not in the upstream config", name, symbol),
+        }
+    }
+}
+
+/// merge_config.sh: the fragment symbols are removed from the base then appended
+pub fn merge(base: &str, fragment: &str) -> String {
+    let fragment_entries = entries(fragment);
+    let mut res: Vec<&str> = base
+        .lines()
+        .filter(|line| parse_line(line).is_none_or(|(s, _)| !fragment_entries.contains_key(s)))
+        .collect();
+    res.extend(fragment.lines().filter(|line| parse_line(line).is_some()));
+    let mut res = res.join("\n");
+    res.push('\n');
+    res
+}
+
+/// Fragment lines of the symbols changed from `old` to `new`
+pub fn diff(old: &str, new: &str) -> String {
+    let old = entries(old);
+    let mut res = String::new();
+    for (symbol, value) in entries(new) {
+        if old.get(symbol) != Some(&value) {
+            match value {
+                Some(value) => res.push_str(&format!("{}={}\n", symbol, value)),
+                None => res.push_str(&format!("# {} is not set\n", symbol)),
+            }
+        }
+    }
+    res
+}
+
+pub fn fragment_path(conf: &Conf, name: &str) -> PathBuf {
+    conf.conf_dir
+        .join("kconfig")
+        .join(format!("{}.config", name))
+}
+
+// Last merged upstream config, to find the new options
+fn snapshot_path(conf: &Conf, name: &str) -> PathBuf {
+    conf.server_dir
+        .join("cache")
+        .join("kconfig")
+        .join(format!("{}.config", name))
+}
+
+/// `config` source of the PKGBUILD, `config.<arch>` for some packages
+pub fn config_source(pkg_dir: &Path) -> Option<PathBuf> {
+    [
+        "config".to_string(),
+        format!("config.{}", std::env::consts::ARCH),
+    ]
+    .into_iter()
+    .map(|file| pkg_dir.join(file))
+    .find(|path| path.is_file())
+}
+
+/// Prepared kernel sources of a package, the dir with the top level Kconfig
+pub fn kernel_src(conf: &Conf, name: &str) -> Option<PathBuf> {
+    fs::read_dir(conf.pkg_src(name).join("src"))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.join("Kconfig").is_file() && path.join(".config").is_file())
+}
+
+/// Merge the fragment into the package config, false if there is no fragment
+pub fn apply(conf: &Conf, name: &str) -> Result<bool, io::Error> {
+    let fragment = match fs::read_to_string(fragment_path(conf, name)) {
+        Ok(fragment) => fragment,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => Err(e)?,
+    };
+    let Some(source) = config_source(&conf.pkg_dir(name)) else {
+        Err(io::Error::new(
+            ErrorKind::NotFound,
+            "No config source in the package",
+        ))?
+    };
+    let orig = PathBuf::from(format!("{}.{}", source.display(), ORIG_EXTENSION));
+    let upstream = if orig.exists() {
+        fs::read_to_string(&orig)?
+    } else {
+        let upstream = fs::read_to_string(&source)?;
+        fs::write(&orig, &upstream)?;
+        upstream
+    };
+    let snapshot = snapshot_path(conf, name);
+    if let Ok(previous) = fs::read_to_string(&snapshot) {
+        let previous = symbols(&previous);
+        let new: Vec<&str> = symbols(&upstream)
+            .into_iter()
+            .filter(|s| !previous.contains(s))
+            .collect();
+        if !new.is_empty() {
+            warn!(
+                "[{}] {} new upstream kernel options: {}",
+                name,
+                new.len(),
+                new.join(" ")
+            );
+        }
+    }
+    if let Some(parent) = snapshot.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(snapshot, &upstream)?;
+    check_fragment(name, &upstream, &fragment);
+    fs::write(source, merge(&upstream, &fragment))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "#
+# Automatically generated file; DO NOT EDIT.
+#
+CONFIG_HZ_300=y
+# CONFIG_HZ_1000 is not set
+CONFIG_HZ=300
+CONFIG_LOCALVERSION=\"\"
+";
+
+    #[test]
+    fn merge_fragment() {
+        let fragment =
+            "# Low latency\nCONFIG_HZ_1000=y\n# CONFIG_HZ_300 is not set\nCONFIG_HZ=1000\n";
+        let merged = merge(BASE, fragment);
+        assert_eq!(
+            merged,
+            "#
+# Automatically generated file; DO NOT EDIT.
+#
+CONFIG_LOCALVERSION=\"\"
+CONFIG_HZ_1000=y
+# CONFIG_HZ_300 is not set
+CONFIG_HZ=1000
+"
+        );
+        // Merging the diff gives back the same config
+        let changes = diff(BASE, &merged);
+        assert_eq!(
+            changes,
+            "CONFIG_HZ=1000\nCONFIG_HZ_1000=y\n# CONFIG_HZ_300 is not set\n"
+        );
+        assert_eq!(entries(&merge(BASE, &changes)), entries(&merged));
+        assert_eq!(diff(BASE, BASE), "");
+    }
+}
@@ -1,9 +1,17 @@
+pub mod binary_cache;
 pub mod builder;
 pub mod cmd;
+pub mod compression;
 pub mod db;
 pub mod download;
+pub mod fingerprint;
 pub mod format;
+pub mod kconfig;
 pub mod patch;
+pub mod pkgrel;
+pub mod revdep;
+pub mod sign;
+pub mod use_flags;
 pub mod utils;
 
 pub mod conf;
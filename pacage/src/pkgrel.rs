@@ -0,0 +1,197 @@
+/*
+Local pkgrel suffix, `pkgrel_suffix = 1` globally or per package: a `pkgrel=2` PKGBUILD is
+built as 2.1, newer than the official 2-release so pacman keeps the pacage build, but older
+than an official 3-release (see `pacage status`).
+The upstream pkgrel is kept in the generated PKGBUILD section:
+
+==== PKGBUILD ====
+[...]
+# pacage: pkgrel 2
+pkgrel=2.1
+========
+A same-version rebuild (new build inputs, broken reverse dependency, -f) bumps the local
+pkgrel of the repo package, 2.1 -> 2.2, without suffix 2 -> 2.1: pacman only upgrades to a new
+version, and the cached package files of the old one must not change.
+*/
+
+use log::warn;
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::conf::Conf;
+use crate::format::{replace_section, SrcInfo};
+use crate::utils::version::Version;
+
+const MARKER: &str = "# pacage: pkgrel";
+
+/// "2" -> "2.1", None if upstream already has a minor: makepkg accepts only one dot
+pub fn local_pkgrel(pkgrel: &str, suffix: u32) -> Option<String> {
+    (!pkgrel.contains('.')).then(|| format!("{}.{}", pkgrel, suffix))
+}
+
+// Upstream pkgrel, kept in the section once the local one is set
+fn upstream(content: &str, srcinfo: &SrcInfo) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(MARKER))
+        .map(|pkgrel| pkgrel.trim().to_string())
+        .or_else(|| srcinfo.pkgrel.clone())
+}
+
+/// Set the local pkgrel in the PKGBUILD, the .SRCINFO and the srcinfo
+pub fn apply(conf: &Conf, srcinfo: &mut SrcInfo) -> Result<(), io::Error> {
+    let path = conf.pkgs_dir().pkg(&srcinfo.name).join("PKGBUILD");
+    let content = fs::read_to_string(&path)?;
+    let Some(upstream) = upstream(&content, srcinfo) else {
+        return Ok(());
+    };
+    let pkgrel = conf.pkgrel_suffix(&srcinfo.name).and_then(|suffix| {
+        let pkgrel = local_pkgrel(&upstream, suffix);
+        if pkgrel.is_none() {
+            warn!(
+                "[{}] pkgrel {} already has a minor, not suffixed",
+                srcinfo.name, upstream
+            );
+        }
+        pkgrel
+    });
+    write(conf, srcinfo, &content, &upstream, pkgrel)
+}
+
+/// Whether the repo version is a rebuild of version: same upstream release, local suffix
+/// not lower ("2.2" of "2.1" or "2")
+pub fn is_rebuild_of(repo: &Version, version: &Version) -> bool {
+    let (Some(repo_rel), Some(rel)) = (repo.release(), version.release()) else {
+        return false;
+    };
+    let Some((major, minor)) = repo_rel.split_once('.') else {
+        return false;
+    };
+    let (rel_major, rel_minor) = rel.split_once('.').unwrap_or((rel, "0"));
+    let (Ok(minor), Ok(rel_minor)) = (minor.parse::<u32>(), rel_minor.parse::<u32>()) else {
+        return false;
+    };
+    major == rel_major && minor >= rel_minor && *repo == version.with_release(repo_rel)
+}
+
+/// Rebuild of the repo version (same inputs version, see is_rebuild_of): bump its local
+/// pkgrel, "2.1" -> "2.2", the new package file has a new version that pacman upgrades to.
+/// False when upstream already has a minor, rebuilt with the same version.
+pub fn bump(conf: &Conf, srcinfo: &mut SrcInfo, repo: &Version) -> Result<bool, io::Error> {
+    let path = conf.pkgs_dir().pkg(&srcinfo.name).join("PKGBUILD");
+    let content = fs::read_to_string(&path)?;
+    let Some(upstream) = upstream(&content, srcinfo) else {
+        return Ok(false);
+    };
+    let minor = repo
+        .release()
+        .and_then(|release| release.split_once('.'))
+        .and_then(|(_, minor)| minor.parse::<u32>().ok())
+        .unwrap_or(0);
+    let Some(pkgrel) = local_pkgrel(&upstream, minor + 1) else {
+        warn!(
+            "[{}] pkgrel {} already has a minor, rebuilt with the same version",
+            srcinfo.name, upstream
+        );
+        return Ok(false);
+    };
+    write(conf, srcinfo, &content, &upstream, Some(pkgrel))?;
+    Ok(true)
+}
+
+/// PKGBUILD content without the local pkgrel, the build inputs of every pkgrel bump
+pub fn strip(content: &str) -> String {
+    replace_section(content, MARKER, None)
+}
+
+fn write(
+    conf: &Conf,
+    srcinfo: &mut SrcInfo,
+    content: &str,
+    upstream: &str,
+    pkgrel: Option<String>,
+) -> Result<(), io::Error> {
+    let pkg_dir = conf.pkgs_dir().pkg(&srcinfo.name);
+    let section = pkgrel
+        .as_ref()
+        .map(|pkgrel| format!("{} {}\npkgrel={}\n", MARKER, upstream, pkgrel));
+    let new = replace_section(content, MARKER, section.as_deref());
+    if new != content {
+        fs::write(pkg_dir.join("PKGBUILD"), new)?;
+    }
+    let pkgrel = pkgrel.unwrap_or_else(|| upstream.to_string());
+
+    // The .SRCINFO is not regenerated yet
+    let srcinfo_path = pkg_dir.join(".SRCINFO");
+    match fs::read_to_string(&srcinfo_path) {
+        Ok(content) => {
+            let mut new: String = content
+                .lines()
+                .map(|line| match line.trim_start().starts_with("pkgrel =") {
+                    true => format!("\tpkgrel = {}", pkgrel),
+                    false => line.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            new.push('\n');
+            if new != content {
+                fs::write(&srcinfo_path, new)?;
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => Err(e)?,
+    }
+    srcinfo.set_pkgrel(&pkgrel);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffix() {
+        assert_eq!(local_pkgrel("2", 1).as_deref(), Some("2.1"));
+        assert_eq!(local_pkgrel("2.1", 1), None);
+        let local = Version::new("1.0", local_pkgrel("2", 1).as_deref(), None);
+        assert!(local > Version::new("1.0", Some("2"), None));
+        assert!(local < Version::new("1.0", Some("3"), None));
+    }
+
+    #[test]
+    fn rebuild_of() {
+        let v = |release| Version::new("1.0", Some(release), None);
+        assert!(is_rebuild_of(&v("2.2"), &v("2.1")));
+        assert!(is_rebuild_of(&v("2.1"), &v("2")));
+        assert!(is_rebuild_of(&v("2.1"), &v("2.1")));
+        assert!(!is_rebuild_of(&v("2"), &v("2")));
+        assert!(!is_rebuild_of(&v("2.1"), &v("2.2")));
+        assert!(!is_rebuild_of(&v("2.1"), &v("3")));
+        assert!(!is_rebuild_of(
+            &Version::new("1.1", Some("2.1"), None),
+            &v("2")
+        ));
+    }
+
+    #[test]
+    fn bump_and_strip() {
+        let conf = Conf::rand();
+        let name = format!("pacage-pkgrel-{}", std::process::id());
+        let pkg_dir = conf.pkgs_dir().pkg(&name);
+        fs::create_dir_all(&pkg_dir).unwrap();
+        let pkgbuild = format!("pkgname={}\npkgver=1.0\npkgrel=2\narch=(any)\n", name);
+        fs::write(pkg_dir.join("PKGBUILD"), &pkgbuild).unwrap();
+        let mut srcinfo = SrcInfo::new(&conf.pkgs_dir(), &name).unwrap();
+        let repo = srcinfo.get_version().clone();
+
+        assert!(bump(&conf, &mut srcinfo, &repo).unwrap());
+        assert_eq!(srcinfo.pkgrel.as_deref(), Some("2.1"));
+        let repo = srcinfo.get_version().clone();
+        assert!(bump(&conf, &mut srcinfo, &repo).unwrap());
+        assert_eq!(srcinfo.pkgrel.as_deref(), Some("2.2"));
+        let content = fs::read_to_string(pkg_dir.join("PKGBUILD")).unwrap();
+        assert!(content.ends_with("# pacage: pkgrel 2\npkgrel=2.2\n"));
+        assert_eq!(strip(&content), pkgbuild);
+        fs::remove_dir_all(pkg_dir).ok();
+    }
+}
@@ -0,0 +1,217 @@
+/*
+Reverse dependencies of the shared libraries, like revdep-rebuild: the ELF files of the repo
+packages are scanned for their NEEDED sonames. A package is broken when one of them is
+provided neither by a repo package (<repo>.files) nor by an official one (host sync .files
+databases, pacman -Fy), ex: a rebuilt library bumped its soname.
+The sonames of a package file are cached by its sha256 (Conf::revdep_cache), only the new
+package files are decompressed:
+
+==== cache/revdep/<target> ====
+<sha256>\t<NEEDED sonames>\t<SONAME sonames>
+[..]
+========
+*/
+
+use log::{error, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use tar::{Archive, EntryType};
+use thiserror::Error;
+
+use crate::compression::Compression;
+use crate::conf::{Conf, Target};
+use crate::db::{self, RepoError};
+use crate::format::{ElfDynamic, ELF_MAGIC};
+
+// Where the dynamic linker looks for the sonames
+const LIB_DIR: &str = "usr/lib/";
+
+#[derive(Debug, Error)]
+pub enum RevdepError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Repo error: {0}")]
+    Repo(#[from] RepoError),
+
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+}
+
+#[derive(Debug)]
+pub struct Broken {
+    pub name: String,
+    pub base: String,
+    // Sonames nothing provides
+    pub missing: BTreeSet<String>,
+}
+
+/// (NEEDED, SONAME) of the ELF files of a package archive, NEEDED without the provided ones
+pub fn sonames(pkgfile: &Path) -> Result<(BTreeSet<String>, BTreeSet<String>), RevdepError> {
+    let name = pkgfile.to_string_lossy();
+    let compression = Compression::from_file_name(&name)
+        .ok_or_else(|| RevdepError::Encoding(format!("Unknown package compression: {}", name)))?;
+    let mut archive = Archive::new(compression.decoder(File::open(pkgfile)?)?);
+    let mut needed = BTreeSet::new();
+    let mut provided = BTreeSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+        let mut data = vec![0; ELF_MAGIC.len()];
+        if entry.read_exact(&mut data).is_err() || data != ELF_MAGIC {
+            continue;
+        }
+        entry.read_to_end(&mut data)?;
+        if let Some(dynamic) = ElfDynamic::parse(&data) {
+            needed.extend(dynamic.needed);
+            provided.extend(dynamic.soname);
+        }
+    }
+    Ok((needed.difference(&provided).cloned().collect(), provided))
+}
+
+type Sonames = (BTreeSet<String>, BTreeSet<String>);
+
+fn read_cache(path: &Path) -> Result<HashMap<String, Sonames>, io::Error> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => Err(e)?,
+    };
+    let set = |sonames: &str| sonames.split_whitespace().map(str::to_string).collect();
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (sha256, needed, provided) = (fields.next()?, fields.next()?, fields.next()?);
+            Some((sha256.to_string(), (set(needed), set(provided))))
+        })
+        .collect())
+}
+
+// Only the scanned entries, the removed package files are dropped
+fn write_cache(path: &Path, cache: &HashMap<String, Sonames>) -> Result<(), io::Error> {
+    let join = |sonames: &BTreeSet<String>| sonames.iter().cloned().collect::<Vec<_>>().join(" ");
+    let mut content = String::new();
+    for (sha256, (needed, provided)) in cache {
+        content.push_str(&format!(
+            "{}\t{}\t{}\n",
+            sha256,
+            join(needed),
+            join(provided)
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("part");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
+
+// usr/lib/libfoo.so.1 -> libfoo.so.1
+fn lib_name(file: &str) -> Option<&str> {
+    let name = file.strip_prefix(LIB_DIR)?;
+    (!name.is_empty() && !name.ends_with('/')).then(|| name.rsplit('/').next().unwrap_or(name))
+}
+
+/// Repo packages linked against a missing soname
+pub fn broken(conf: &Conf, target: &Target) -> Result<Vec<Broken>, RevdepError> {
+    let mut libs = BTreeSet::new();
+    let mut add_lib = |file: &str| {
+        if let Some(name) = lib_name(file) {
+            libs.insert(name.to_string());
+        }
+    };
+    db::read_repo_files(conf, target, &mut add_lib)?;
+    let official = db::sync_files_dbs(conf).unwrap_or_default();
+    for (repo, path) in &official {
+        if let Err(e) = db::read_files_db(path, &mut add_lib) {
+            warn!("Failed to read the {} files database: {}", repo, e);
+        }
+    }
+    if official.is_empty() {
+        warn!("No host files databases (pacman -Fy), checking the host /usr/lib instead");
+    }
+
+    let cache_path = conf.revdep_cache(target);
+    let mut cache = read_cache(&cache_path).unwrap_or_else(|e| {
+        warn!("Failed to read {}: {}", cache_path.display(), e);
+        HashMap::new()
+    });
+    let mut scanned = Vec::new();
+    let mut new_cache = HashMap::new();
+    for desc in db::list(conf, target)? {
+        let sonames = match cache.remove(&desc.shasum) {
+            Some(sonames) => sonames,
+            None => match sonames(&conf.repo_dir(target).join(&desc.filename)) {
+                Ok(sonames) => sonames,
+                Err(e) => {
+                    error!("[{}] Failed to scan {}: {}", desc.name, desc.filename, e);
+                    continue;
+                }
+            },
+        };
+        // The soname symlinks could be missing from the files database
+        libs.extend(sonames.1.iter().cloned());
+        new_cache.insert(desc.shasum.clone(), sonames.clone());
+        scanned.push((desc, sonames.0));
+    }
+    if let Err(e) = write_cache(&cache_path, &new_cache) {
+        warn!("Failed to write {}: {}", cache_path.display(), e);
+    }
+
+    let mut res = Vec::new();
+    for (desc, needed) in scanned {
+        let missing: BTreeSet<String> = needed
+            .into_iter()
+            .filter(|soname| !libs.contains(soname))
+            .filter(|soname| {
+                !official.is_empty() || !Path::new("/").join(LIB_DIR).join(soname).exists()
+            })
+            .collect();
+        if !missing.is_empty() {
+            res.push(Broken {
+                base: desc.base.clone().unwrap_or_else(|| desc.name.clone()),
+                name: desc.name,
+                missing,
+            });
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::tests::mktemp;
+
+    #[test]
+    fn lib_names() {
+        assert_eq!(lib_name("usr/lib/libfoo.so.1"), Some("libfoo.so.1"));
+        assert_eq!(lib_name("usr/lib/foo/libbar.so"), Some("libbar.so"));
+        assert_eq!(lib_name("usr/lib/foo/"), None);
+        assert_eq!(lib_name("usr/bin/foo"), None);
+    }
+
+    #[test]
+    fn cache() {
+        let path = mktemp().join("revdep").join("pacage");
+        assert!(read_cache(&path).unwrap().is_empty());
+        let set = |sonames: &[&str]| sonames.iter().map(|s| s.to_string()).collect();
+        let cache = HashMap::from([
+            (
+                "ab".to_string(),
+                (set(&["libc.so.6", "libfoo.so.1"]), set(&[])),
+            ),
+            ("cd".to_string(), (set(&[]), set(&["libfoo.so.1"]))),
+        ]);
+        write_cache(&path, &cache).unwrap();
+        assert_eq!(read_cache(&path).unwrap(), cache);
+        fs::remove_dir_all(path.parent().unwrap().parent().unwrap()).ok();
+    }
+}
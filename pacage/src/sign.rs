@@ -0,0 +1,137 @@
+/*
+Detached PGP signatures of the packages and of the repo databases, like makepkg --sign and
+repo-add --sign: `gpg_key = "<key id>"`, the keyring is the user one unless `gpg_home` is set.
+
+==== repo/ ====
+├ vi-1:070224-6-x86_64.pkg.tar.zst
+├ vi-1:070224-6-x86_64.pkg.tar.zst.sig    # Also base64'd in the db desc (%PGPSIG%)
+├ pacage.db -> pacage.db.tar.gz           # Links replaced atomically, db_compression
+├ pacage.db.tar.gz
+├ pacage.db.tar.gz.sig
+├ pacage.db.sig -> pacage.db.tar.gz.sig
+├ pacage.files -> pacage.files.tar.gz
+├ pacage.files.tar.gz
+├ pacage.files.tar.gz.sig
+└ pacage.files.sig -> pacage.files.tar.gz.sig
+========
+*/
+
+use base64ct::{Base64, Encoding};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use thiserror::Error;
+
+use crate::conf::Conf;
+
+#[derive(Debug, Error)]
+pub enum SignError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("gpg failed: {0}")]
+    Gpg(String),
+}
+
+pub fn sig_path(path: &Path) -> PathBuf {
+    let mut sig = path.as_os_str().to_owned();
+    sig.push(".sig");
+    PathBuf::from(sig)
+}
+
+/// Detached signature of path into <path>.sig, nothing without gpg_key
+pub fn sign(conf: &Conf, path: &Path) -> Result<Option<PathBuf>, SignError> {
+    let Some(key) = &conf.gpg_key else {
+        return Ok(None);
+    };
+    let sig = sig_path(path);
+    let mut cmd = Command::new("gpg");
+    if let Some(home) = &conf.gpg_home {
+        cmd.arg("--homedir").arg(home);
+    }
+    let out = cmd
+        .args([
+            "--batch",
+            "--yes",
+            "--no-armor",
+            "--detach-sign",
+            "--local-user",
+            key,
+        ])
+        .arg("--output")
+        .arg(&sig)
+        .arg(path)
+        .output()?;
+    if !out.status.success() {
+        return Err(SignError::Gpg(
+            String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        ));
+    }
+    Ok(Some(sig))
+}
+
+/// %PGPSIG% value of a signature file
+pub fn pgpsig(sig: &Path) -> Result<String, io::Error> {
+    Ok(Base64::encode_string(&fs::read(sig)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::tests::mktemp;
+
+    #[test]
+    fn sign_and_verify() {
+        let home = mktemp();
+        let gpg = |args: &[&str]| {
+            Command::new("gpg")
+                .arg("--homedir")
+                .arg(&home)
+                .args(["--batch", "--passphrase", ""])
+                .args(args)
+                .output()
+                .unwrap()
+                .status
+                .success()
+        };
+        // Throw-away keyring
+        assert!(gpg(&[
+            "--quick-gen-key",
+            "pacage test",
+            "ed25519",
+            "sign",
+            "never"
+        ]));
+
+        let mut conf = Conf::rand();
+        let file = home.join("foo-1.0-1-x86_64.pkg.tar.zst");
+        fs::write(&file, "package").unwrap();
+        assert_eq!(sign(&conf, &file).unwrap(), None);
+
+        conf.gpg_key = Some("pacage test".to_string());
+        conf.gpg_home = Some(home.clone());
+        let sig = sign(&conf, &file).unwrap().unwrap();
+        assert_eq!(sig, home.join("foo-1.0-1-x86_64.pkg.tar.zst.sig"));
+        assert!(gpg(&[
+            "--verify",
+            &sig.to_string_lossy(),
+            &file.to_string_lossy()
+        ]));
+        assert_eq!(
+            Base64::decode_vec(&pgpsig(&sig).unwrap()).unwrap(),
+            fs::read(&sig).unwrap()
+        );
+
+        conf.gpg_key = Some("unknown".to_string());
+        assert!(matches!(sign(&conf, &file), Err(SignError::Gpg(_))));
+
+        Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(&home)
+            .args(["--kill", "gpg-agent"])
+            .output()
+            .ok();
+        fs::remove_dir_all(home).ok();
+    }
+}
@@ -0,0 +1,333 @@
+/*
+USE flags, portage style: `use = ["-x11", "wayland"]` globally and per package.
+The meaning of each flag is given by a mapping file per package, <conf_dir>/use/<pkg>.toml:
+
+==== use/foo.toml ====
+[x11]
+default = true
+on = { configure = ["--with-x"], meson = ["-Dx11=enabled"] }
+off = { configure = ["--without-x"], meson = ["-Dx11=disabled"], remove_depends = ["libx11"] }
+
+[wayland]
+on = { cmake = ["-DWITH_WAYLAND=ON"] }
+off = { remove_makedepends = ["wayland-protocols"] }
+========
+
+Flags are applied by appending a snippet to the PKGBUILD, it wraps ./configure, meson,
+arch-meson and cmake to add the arguments, and filters the top level depends/makedepends.
+*/
+
+use log::warn;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::conf::{Conf, PkgsDir};
+use crate::format::{replace_section, SrcInfo};
+
+// Start of the generated PKGBUILD section
+const MARKER: &str = "# pacage: USE flags";
+
+const REMOVE_FN: &str = r#"_pacage_use_remove() {
+  local -n _pacage_array=$1
+  shift
+  local _pacage_keep=() _pacage_dep _pacage_rm
+  for _pacage_dep in "${_pacage_array[@]}"; do
+    for _pacage_rm in "$@"; do
+      [[ ${_pacage_dep%%[<>=]*} == "$_pacage_rm" ]] && continue 2
+    done
+    _pacage_keep+=("$_pacage_dep")
+  done
+  _pacage_array=("${_pacage_keep[@]}")
+}
+"#;
+const CONFIGURE_FN: &str = r#"./configure() { command ./configure "$@" "${_pacage_use_configure[@]}"; }
+"#;
+const MESON_FN: &str = r#"arch-meson() { command arch-meson "$@" "${_pacage_use_meson[@]}"; }
+meson() {
+  if [[ $1 == setup ]]; then
+    command meson "$@" "${_pacage_use_meson[@]}"
+  else
+    command meson "$@"
+  fi
+}
+"#;
+const CMAKE_FN: &str = r#"cmake() {
+  case $1 in
+    --build|--install|-E|-P) command cmake "$@" ;;
+    *) command cmake "$@" "${_pacage_use_cmake[@]}" ;;
+  esac
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum UseError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid USE mapping: {0}")]
+    Mapping(#[from] toml::de::Error),
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct UseAction {
+    pub configure: Vec<String>,
+    pub meson: Vec<String>,
+    pub cmake: Vec<String>,
+    pub remove_depends: Vec<String>,
+    pub remove_makedepends: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct UseFlag {
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub on: UseAction,
+    #[serde(default)]
+    pub off: UseAction,
+}
+
+#[derive(Debug, Default)]
+pub struct UseFlags {
+    // Only the flags known by the mapping file
+    pub flags: BTreeMap<String, bool>,
+    mapping: BTreeMap<String, UseFlag>,
+}
+
+// "-x11" -> ("x11", false), "+x11"/"x11" -> ("x11", true)
+fn parse_flag(flag: &str) -> (&str, bool) {
+    if let Some(flag) = flag.strip_prefix('-') {
+        (flag, false)
+    } else {
+        (flag.strip_prefix('+').unwrap_or(flag), true)
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn bash_words(items: &[&String]) -> String {
+    items
+        .iter()
+        .map(|a| quote(a))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// "libx11>=1.8" -> "libx11"
+fn dep_name(dep: &str) -> &str {
+    dep.split(['<', '>', '=']).next().unwrap_or(dep)
+}
+
+impl UseFlags {
+    pub fn new(conf: &Conf, name: &str) -> Result<Self, UseError> {
+        let path = conf.conf_dir.join("use").join(format!("{}.toml", name));
+        let mapping = match fs::read_to_string(path) {
+            Ok(mapping) => mapping,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => Err(e)?,
+        };
+        let pkg_flags = conf.find(name).and_then(|p| p.use_flags.as_deref());
+        Self::from_mapping(name, &mapping, &conf.use_flags, pkg_flags)
+    }
+
+    pub fn from_mapping(
+        name: &str,
+        mapping: &str,
+        global: &[String],
+        pkg: Option<&[String]>,
+    ) -> Result<Self, UseError> {
+        let mapping: BTreeMap<String, UseFlag> = toml::from_str(mapping)?;
+        let mut flags: BTreeMap<String, bool> = mapping
+            .iter()
+            .map(|(flag, m)| (flag.clone(), m.default))
+            .collect();
+        // Global flags can target other packages
+        for (flag, enabled) in global.iter().map(|f| parse_flag(f)) {
+            if let Some(value) = flags.get_mut(flag) {
+                *value = enabled;
+            }
+        }
+        for (flag, enabled) in pkg.unwrap_or_default().iter().map(|f| parse_flag(f)) {
+            match flags.get_mut(flag) {
+                Some(value) => *value = enabled,
+                None => warn!("[{}] Unknown USE flag: {}", name, flag),
+            }
+        }
+        Ok(Self { flags, mapping })
+    }
+
+    /// Effective flags, "-flag" when disabled
+    pub fn list(&self) -> Vec<String> {
+        self.flags
+            .iter()
+            .map(|(flag, enabled)| {
+                if *enabled {
+                    flag.clone()
+                } else {
+                    format!("-{}", flag)
+                }
+            })
+            .collect()
+    }
+
+    fn actions(&self) -> impl Iterator<Item = &UseAction> {
+        self.flags.iter().filter_map(|(flag, enabled)| {
+            let m = self.mapping.get(flag)?;
+            Some(if *enabled { &m.on } else { &m.off })
+        })
+    }
+
+    fn collect<'a>(&'a self, f: fn(&'a UseAction) -> &'a Vec<String>) -> Vec<&'a String> {
+        self.actions().flat_map(f).collect()
+    }
+
+    /// Bash appended to the PKGBUILD, None when the flags change nothing
+    pub fn pkgbuild_snippet(&self) -> Option<String> {
+        let configure = self.collect(|a| &a.configure);
+        let meson = self.collect(|a| &a.meson);
+        let cmake = self.collect(|a| &a.cmake);
+        let depends = self.collect(|a| &a.remove_depends);
+        let makedepends = self.collect(|a| &a.remove_makedepends);
+        if [&configure, &meson, &cmake, &depends, &makedepends]
+            .iter()
+            .all(|a| a.is_empty())
+        {
+            return None;
+        }
+        let mut res = format!("{} {}\n", MARKER, self.list().join(" "));
+        if !depends.is_empty() || !makedepends.is_empty() {
+            res.push_str(REMOVE_FN);
+        }
+        if !depends.is_empty() {
+            res.push_str(&format!(
+                "_pacage_use_remove depends {}\n",
+                bash_words(&depends)
+            ));
+        }
+        if !makedepends.is_empty() {
+            res.push_str(&format!(
+                "_pacage_use_remove makedepends {}\n",
+                bash_words(&makedepends)
+            ));
+        }
+        for (args, var, wrapper) in [
+            (&configure, "_pacage_use_configure", CONFIGURE_FN),
+            (&meson, "_pacage_use_meson", MESON_FN),
+            (&cmake, "_pacage_use_cmake", CMAKE_FN),
+        ] {
+            if !args.is_empty() {
+                res.push_str(&format!("{}=({})\n", var, bash_words(args)));
+                res.push_str(wrapper);
+            }
+        }
+        Some(res)
+    }
+
+    /// Replace the previous pacage snippet of the PKGBUILD by the current one
+    pub fn write_pkgbuild(&self, path: &Path) -> Result<(), io::Error> {
+        let content = fs::read_to_string(path)?;
+        let new = replace_section(&content, MARKER, self.pkgbuild_snippet().as_deref());
+        if new != content {
+            fs::write(path, new)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the flags to a freshly fetched package
+    pub fn apply(&self, pkgs_dir: &PkgsDir, mut srcinfo: SrcInfo) -> Result<SrcInfo, UseError> {
+        self.write_pkgbuild(&pkgs_dir.pkg(&srcinfo.name).join("PKGBUILD"))?;
+        // The .SRCINFO is not regenerated yet
+        let removed = self.collect(|a| &a.remove_depends);
+        srcinfo
+            .deps
+            .retain(|dep| !removed.iter().any(|r| *r == dep_name(dep)));
+        Ok(srcinfo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::tests::mktemp;
+    use std::process::Command;
+
+    const MAPPING: &str = r#"
+[x11]
+default = true
+on = { configure = ["--with-x"] }
+off = { configure = ["--without-x"], remove_depends = ["libx11"] }
+
+[wayland]
+on = { meson = ["-Dwayland=enabled"] }
+off = { remove_makedepends = ["wayland-protocols"] }
+
+[doc]
+default = true
+"#;
+
+    fn strings(a: &[&str]) -> Vec<String> {
+        a.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn effective_flags() {
+        let flags = UseFlags::from_mapping("foo", MAPPING, &[], None).unwrap();
+        assert_eq!(flags.list(), strings(&["doc", "-wayland", "x11"]));
+
+        let global = strings(&["-x11", "wayland", "-unknown"]);
+        let flags = UseFlags::from_mapping("foo", MAPPING, &global, None).unwrap();
+        assert_eq!(flags.list(), strings(&["doc", "wayland", "-x11"]));
+
+        let pkg = strings(&["+x11", "-doc"]);
+        let flags = UseFlags::from_mapping("foo", MAPPING, &global, Some(&pkg)).unwrap();
+        assert_eq!(flags.list(), strings(&["-doc", "wayland", "x11"]));
+    }
+
+    #[test]
+    fn pkgbuild() {
+        let dir = mktemp();
+        let path = dir.join("PKGBUILD");
+        fs::write(
+            &path,
+            "pkgname=foo\ndepends=('glibc' 'libx11>=1.8')\nmakedepends=(meson wayland-protocols)\n",
+        )
+        .unwrap();
+        let eval = || {
+            let out = Command::new("bash")
+                .args([
+                    "-c",
+                    "source PKGBUILD; echo ${depends[@]}; echo ${makedepends[@]}; type -t ./configure",
+                ])
+                .current_dir(&dir)
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        let global = strings(&["-x11"]);
+        let flags = UseFlags::from_mapping("foo", MAPPING, &global, None).unwrap();
+        flags.write_pkgbuild(&path).unwrap();
+        // Applying twice replace the previous snippet
+        flags.write_pkgbuild(&path).unwrap();
+        assert_eq!(eval(), "glibc\nmeson\nfunction\n");
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches(MARKER).count(), 1);
+        assert!(content.contains("_pacage_use_configure=('--without-x')"));
+
+        let flags = UseFlags::from_mapping("foo", MAPPING, &[], None).unwrap();
+        flags.write_pkgbuild(&path).unwrap();
+        assert_eq!(eval(), "glibc libx11>=1.8\nmeson\nfunction\n");
+
+        let flags = UseFlags::default();
+        flags.write_pkgbuild(&path).unwrap();
+        assert_eq!(eval(), "glibc libx11>=1.8\nmeson wayland-protocols\n");
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -1,33 +1,92 @@
-use std::{fs::File, io, path::PathBuf};
+/*
+flock(2) locks shared with the other pacage instances, released when dropped or when the process
+dies. The lock files are never removed: another instance could be waiting on the old inode.
+$XDG_RUNTIME_DIR/pacage_builder.lock      # Builder container name, shared by every server_dir
+$XDG_RUNTIME_DIR/pacage_pgo_<pkg>.lock    # PGO training container name
+==== <server_dir>/ ====
+├ srcs/
+│ └ <pkg>.lock             # Sources of the package (srcs/<pkg>/)
+└ repo/
+  └ pacage.lock/           # Repo dbs (DirLock), the new dbs are written in it
+    └ lock
+========
+*/
 
-pub struct FileLock(PathBuf);
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
 
-impl FileLock {
-    pub fn new(path: PathBuf) -> Result<Self, io::Error> {
-        File::create_new(&path).map(|_| Self(path))
-    }
+pub struct FileLock {
+    // Unlocked when closed
+    _file: File,
 }
 
-impl std::ops::Drop for FileLock {
-    fn drop(&mut self) {
-        std::fs::remove_file(&self.0).ok();
+impl FileLock {
+    /// Lock the file (created if needed), waits for the other instance when wait is set
+    pub fn new(path: &Path, wait: bool) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if wait {
+            file.lock()?;
+            return Ok(Self { _file: file });
+        }
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                format!(
+                    "{} is locked by another pacage instance, retry with --wait",
+                    path.display()
+                ),
+            )),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
     }
 }
 
-pub struct DirLock(PathBuf);
+/// Locked directory, for the temporary files of the locked operation
+pub struct DirLock {
+    path: PathBuf,
+    _lock: FileLock,
+}
 
 impl DirLock {
-    pub fn new(path: PathBuf) -> Result<Self, io::Error> {
-        std::fs::create_dir_all(&path).map(|_| Self(path))
+    pub fn new(path: PathBuf, wait: bool) -> Result<Self, io::Error> {
+        fs::create_dir_all(&path)?;
+        let lock = FileLock::new(&path.join("lock"), wait)?;
+        Ok(Self { path, _lock: lock })
     }
 
     pub fn path(&self) -> &PathBuf {
-        &self.0
+        &self.path
     }
 }
 
-impl std::ops::Drop for DirLock {
-    fn drop(&mut self) {
-        // std::fs::remove_dir_all(&self.0).ok();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::tests::mktemp;
+
+    #[test]
+    fn exclusive() {
+        let dir = mktemp().join("repo.lock");
+        let lock = DirLock::new(dir.clone(), false).unwrap();
+        let err = DirLock::new(dir.clone(), false).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        // Released on drop, the waiting one gets it
+        let waiting = {
+            let dir = dir.clone();
+            std::thread::spawn(move || DirLock::new(dir, true).is_ok())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(lock);
+        assert!(waiting.join().unwrap());
+        assert!(DirLock::new(dir.clone(), false).is_ok());
+        fs::remove_dir_all(dir.parent().unwrap()).ok();
     }
 }
@@ -92,6 +92,12 @@ impl Version {
             epoch,
         }
     }
+    pub fn release(&self) -> Option<&str> {
+        self.release.as_deref()
+    }
+    pub fn with_release(&self, release: &str) -> Self {
+        Self::new(&self.version, Some(release), self.epoch)
+    }
     pub fn _cmp(&self, other: &Self) -> Ordering {
         let self_epoch = self.epoch.unwrap_or(0);
         let other_epoch = other.epoch.unwrap_or(0);
@@ -202,6 +208,13 @@ impl Version {
                                     }
                                 }
                             }
+                            // The number ends on both sides, "2.1" < "3"
+                            (Some(a), None) if !a.is_ascii_digit() && res_a != res_b => {
+                                return res_a.cmp(&res_b)
+                            }
+                            (None, Some(b)) if !b.is_ascii_digit() && res_a != res_b => {
+                                return res_a.cmp(&res_b)
+                            }
                             (Some(a), None) => {
                                 if a.is_ascii_alphabetic() {
                                     return Ordering::Less;
@@ -294,6 +307,10 @@ mod tests {
             ("1.5-1", "1.5.1-1", Ordering::Less),
             ("1.5-2", "1.5.1-1", Ordering::Less),
             ("1.5-2", "1.5.1-2", Ordering::Less),
+            // minor pkgrel
+            ("1.5-2.1", "1.5-2", Ordering::Greater),
+            ("1.5-2.1", "1.5-3", Ordering::Less),
+            ("1.5-3", "1.5-2.1", Ordering::Greater),
             // mixed pkgrel inclusion
             ("1.5", "1.5-1", Ordering::Equal),
             ("1.5-1", "1.5", Ordering::Equal),
@@ -1,20 +1,32 @@
 use clap::Args;
+use std::fs;
 
+use crate::util::{build_fingerprint, bump_pkgrel, upload_to_binary_cache};
 use crate::{cmd_err, CliCmd};
 use pacage::builder;
 use pacage::db;
+use pacage::fingerprint;
 use pacage::format::SrcInfo;
 use pacage::patch::patch;
+use pacage::pkgrel;
+use pacage::use_flags::UseFlags;
 
 #[derive(Args, Debug)]
 pub struct Build {
     /// Package name
     pub name: String,
+
+    /// Only build for this target, default: every target
+    #[arg(long)]
+    pub target: Option<String>,
+
+    /// Drop the collected PGO profiles and train again
+    #[arg(long, default_value_t = false)]
+    pub pgo_retrain: bool,
 }
 
 impl CliCmd for Build {
     fn execute(&self, mut conf: crate::Conf) -> Result<(), i32> {
-        let pkg_build = SrcInfo::new(&conf.pkgs_dir(), &self.name, false).map_err(cmd_err)?;
         if !conf.pkg_src(&self.name).exists() {
             Err(cmd_err(format!(
                 "Missing packages sources, run 'pacage download {}' to get them",
@@ -26,16 +38,46 @@ impl CliCmd for Build {
             conf.container_runner.clone(),
             &conf.host_server_dir,
             &conf.build_log_dir,
+            conf.wait_lock,
         )
         .map_err(cmd_err)?;
+        // The USE flags could have changed since the download
+        UseFlags::new(&conf, &self.name)
+            .map_err(cmd_err)?
+            .write_pkgbuild(&conf.pkgs_dir().pkg(&self.name).join("PKGBUILD"))
+            .map_err(cmd_err)?;
+        let mut srcinfo = SrcInfo::new(&conf.pkgs_dir(), &self.name).map_err(cmd_err)?;
+        pkgrel::apply(&conf, &mut srcinfo).map_err(cmd_err)?;
+        let pkg_build = builder.srcinfo(&conf, &self.name).map_err(cmd_err)?;
         patch(&conf, &pkg_build).map_err(cmd_err)?;
         conf.ensure_pkg(&self.name);
         let pkg = conf.get(self.name.as_str());
-        builder
-            .build_pkg(&conf, pkg)
-            // .build_pkg(conf, &self.name, makepkg)
-            .map_err(cmd_err)?;
-        db::add(&conf, &[pkg_build]).map_err(cmd_err)?;
+        let targets = match &self.target {
+            Some(name) => vec![conf.target(Some(name)).map_err(cmd_err)?],
+            None => conf.targets.iter().collect(),
+        };
+        let image = fingerprint::image_digest(&conf);
+        for target in targets {
+            if self.pgo_retrain {
+                let pgo_dir = conf.server_dir.join(conf.pgo_subdir(&pkg.name, target));
+                if pgo_dir.exists() {
+                    fs::remove_dir_all(pgo_dir).map_err(cmd_err)?;
+                }
+            }
+            let mut srcinfo = pkg_build.clone();
+            bump_pkgrel(
+                &conf,
+                &db::list(&conf, target).unwrap_or_default(),
+                &mut srcinfo,
+            );
+            builder
+                .build_pkg(&conf, pkg, target)
+                // .build_pkg(conf, &self.name, makepkg)
+                .map_err(cmd_err)?;
+            srcinfo.fingerprint = build_fingerprint(&conf, pkg, target, image.as_deref());
+            db::add(&conf, target, std::slice::from_ref(&srcinfo)).map_err(cmd_err)?;
+            upload_to_binary_cache(&conf, &srcinfo, target);
+        }
         Ok(())
     }
 }
@@ -35,6 +35,7 @@ impl CliCmd for Get {
         let mut to_dl = BTreeSet::new();
         to_dl.insert(self.name.clone());
 
+        conf.force_rebuild |= self.force_rebuild;
         let builder_recv = Builder::new_async(&conf);
         download_all(&mut conf, to_dl, true, pkgbuildssender).map_err(cmd_err)?;
         let num = dl_and_build(&conf, pkgbuilds, builder_recv, true).map_err(cmd_err)?;
@@ -0,0 +1,74 @@
+use clap::Args;
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::{cmd_err, CliCmd};
+use pacage::builder::BUILDER_IMAGE;
+use pacage::cmd::interactive;
+use pacage::conf::BUILD_SCRIPT_FILE;
+use pacage::kconfig;
+
+#[derive(Args, Debug)]
+pub struct Kconfig {
+    /// Package name
+    pub name: String,
+}
+
+impl CliCmd for Kconfig {
+    fn execute(&self, mut conf: crate::Conf) -> Result<(), i32> {
+        conf.ensure_pkg(&self.name);
+        let Some(kernel_dir) = kconfig::kernel_src(&conf, &self.name) else {
+            eprintln!(
+                "No prepared kernel sources for {}, run 'pacage get {}' first",
+                self.name, self.name
+            );
+            return Err(2);
+        };
+        let config = kernel_dir.join(".config");
+        let before = fs::read_to_string(&config).map_err(cmd_err)?;
+
+        let relative = kernel_dir.strip_prefix(&conf.server_dir).map_err(cmd_err)?;
+        let server_dir = conf.host_server_dir.as_ref().unwrap_or(&conf.server_dir);
+        let status = interactive(
+            &[
+                &conf.container_runner,
+                "run",
+                "-it",
+                "--rm",
+                &format!("-v={}:/build", server_dir.display()),
+                "--workdir=/build",
+                &format!("--env=PACAGE_KCONFIG_DIR=/build/{}", relative.display()),
+                BUILDER_IMAGE,
+                "bash",
+                &format!("/build/{}", BUILD_SCRIPT_FILE),
+                "kconfig",
+                &self.name,
+            ],
+            &conf.server_dir,
+        )
+        .map_err(cmd_err)?;
+        if !status.success() {
+            eprintln!("menuconfig failed: {}", status);
+            return Err(2);
+        }
+
+        let after = fs::read_to_string(&config).map_err(cmd_err)?;
+        let changes = kconfig::diff(&before, &after);
+        if changes.is_empty() {
+            println!("No config changes");
+            return Ok(());
+        }
+        let path = kconfig::fragment_path(&conf, &self.name);
+        let fragment = match fs::read_to_string(&path) {
+            Ok(fragment) => fragment,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(cmd_err(e)),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(cmd_err)?;
+        }
+        fs::write(&path, kconfig::merge(&fragment, &changes)).map_err(cmd_err)?;
+        println!("Config changes saved in {}", path.display());
+        Ok(())
+    }
+}
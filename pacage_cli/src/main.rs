@@ -7,7 +7,13 @@ use pacage::conf::Conf;
 mod build;
 mod clean;
 mod get;
+mod kconfig;
+mod makepkg_conf;
 mod patch;
+mod remove;
+mod repo;
+mod revdep;
+mod rollback;
 mod status;
 mod update;
 pub mod util;
@@ -32,6 +38,14 @@ pub struct Cli {
     #[arg(short)]
     pub force_rebuild: bool,
 
+    /// Replace a newer version already in the repo (ex. pinned PKGBUILD)
+    #[arg(long)]
+    pub allow_downgrade: bool,
+
+    /// Wait for the repo, builder and sources locks held by another pacage instead of failing
+    #[arg(long)]
+    pub wait: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -52,6 +66,25 @@ enum Commands {
 
     /// Clean utilities
     Clean(clean::Clean),
+
+    /// Print the makepkg.conf used to build a package
+    MakepkgConf(makepkg_conf::MakepkgConf),
+
+    /// Edit the kernel config of a package with menuconfig
+    Kconfig(kconfig::Kconfig),
+
+    /// Rebuild the packages linked against a missing library
+    Revdep(revdep::Revdep),
+
+    /// Remove a package from the repo
+    Remove(remove::Remove),
+
+    /// Restore an archived version of a package in the repo
+    Rollback(rollback::Rollback),
+
+    /// Repo database utilities
+    #[command(subcommand)]
+    Repo(repo::Repo),
 }
 
 #[derive(Args, Debug)]
@@ -69,6 +102,12 @@ impl CliCmd for Commands {
             Commands::Status(a) => a.execute(conf),
             Commands::Patch(a) => a.execute(conf),
             Commands::Clean(a) => a.execute(conf),
+            Commands::MakepkgConf(a) => a.execute(conf),
+            Commands::Kconfig(a) => a.execute(conf),
+            Commands::Revdep(a) => a.execute(conf),
+            Commands::Remove(a) => a.execute(conf),
+            Commands::Rollback(a) => a.execute(conf),
+            Commands::Repo(a) => a.execute(conf),
         }
     }
 }
@@ -88,13 +127,16 @@ impl CliCmd for Cli {
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let args = Cli::get();
-    let conf = match Conf::new(args.confdir.as_deref()) {
+    let mut conf = match Conf::new(args.confdir.as_deref()) {
         Ok(c) => c,
         Err(e) => {
             error!("Failed to create conf: {}", e);
             std::process::exit(2);
         }
     };
+    conf.force_rebuild = args.force_rebuild;
+    conf.allow_downgrade = args.allow_downgrade;
+    conf.wait_lock = args.wait;
     if let Err(e) = conf.init() {
         error!("Failed to init: {}", e);
         std::process::exit(2);
@@ -0,0 +1,45 @@
+use clap::Args;
+
+use crate::{cmd_err, CliCmd};
+use pacage::conf::Makepkg;
+
+#[derive(Args, Debug)]
+pub struct MakepkgConf {
+    /// Package name
+    pub name: String,
+
+    /// Show where each setting come from instead of the file
+    #[arg(long, default_value_t = false)]
+    explain: bool,
+
+    /// Target to resolve the settings for, default: the first one
+    #[arg(long)]
+    target: Option<String>,
+}
+
+impl CliCmd for MakepkgConf {
+    fn execute(&self, mut conf: crate::Conf) -> Result<(), i32> {
+        conf.ensure_pkg(&self.name);
+        let pkg = conf.get(&self.name);
+        let target = conf.target(self.target.as_deref()).map_err(cmd_err)?;
+        let resolved = Makepkg::resolve(&conf, pkg, target).map_err(cmd_err)?;
+        if !self.explain {
+            print!("{}", resolved.file);
+            return Ok(());
+        }
+        for (var, origins) in &resolved.origins {
+            let origins: Vec<String> = origins.iter().map(|o| o.to_string()).collect();
+            let value = resolved
+                .file
+                .get(var)
+                .or_else(|| resolved.env.get(var).cloned())
+                .unwrap_or_else(|| match var.as_str() {
+                    "check" if resolved.flags.contains(&"--nocheck") => "false".to_string(),
+                    _ => "true".to_string(),
+                });
+            println!("{}={}", var, value);
+            println!("    <- {}", origins.join(" -> "));
+        }
+        Ok(())
+    }
+}
@@ -48,7 +48,7 @@ impl CliCmd for Open {
         let srcinfo = if !conf.pkg_dir(&pkg.name).exists() {
             fetch_pkg(&pkgsdir, &pkg.name, &pkg.repo).map_err(cmd_err)?
         } else {
-            SrcInfo::new(&pkgsdir, &pkg.name, false).map_err(cmd_err)?
+            SrcInfo::new(&pkgsdir, &pkg.name).map_err(cmd_err)?
         };
         if srcinfo.src == false {
             eprintln!("The package doesnt contain sources");
@@ -60,11 +60,10 @@ impl CliCmd for Open {
             conf.container_runner.clone(),
             &conf.host_server_dir,
             &conf.build_log_dir,
+            conf.wait_lock,
         )
         .map_err(cmd_err)?;
-        let srcinfo = builder
-            .download_src(&conf, srcinfo, pkg.makepkg.as_ref())
-            .map_err(cmd_err)?;
+        let srcinfo = builder.download_src(&conf, srcinfo, pkg).map_err(cmd_err)?;
         drop(builder);
         let Some(orig) = find_src(&conf, &srcinfo) else {
             eprintln!("Failed to find packages sources for {}", pkg.name);
@@ -168,7 +167,7 @@ fn get_diff(
     } else {
         get_pwd_pkg(&conf)?
     };
-    let srcinfo = SrcInfo::new(&conf.pkgs_dir(), &name, false).map_err(cmd_err)?;
+    let srcinfo = SrcInfo::new(&conf.pkgs_dir(), &name).map_err(cmd_err)?;
     let Some(mut orig_path) = find_src(&conf, &srcinfo) else {
         eprintln!("Failed to find packages sources for {}", name);
         return Err(2);
@@ -0,0 +1,86 @@
+use clap::Args;
+use log::{error, warn};
+use std::fs;
+
+use crate::{cmd_err, CliCmd};
+use pacage::db::{self, RemoveError, RepoError};
+
+#[derive(Args, Debug)]
+pub struct Remove {
+    /// Package name (pkgname or pkgbase)
+    pub name: String,
+
+    /// Keep the PKGBUILD and the package sources
+    #[arg(long, default_value_t = false)]
+    pub keep_sources: bool,
+
+    /// Also remove the package from pacage.toml
+    #[arg(long, default_value_t = false)]
+    pub from_conf: bool,
+}
+
+impl CliCmd for Remove {
+    fn execute(&self, conf: crate::Conf) -> Result<(), i32> {
+        let base = conf.resolve(&self.name);
+        let names = vec![self.name.clone(), base.clone()];
+        let mut found = false;
+        for target in &conf.targets {
+            match db::remove(&conf, target, &names) {
+                Ok(removed) => {
+                    found = true;
+                    for desc in removed {
+                        println!(
+                            "Removed {} {} from [{}]",
+                            desc.name, desc.version, target.name
+                        );
+                    }
+                }
+                Err(RemoveError::NotFound(_)) | Err(RemoveError::Repo(RepoError::NoRepo)) => {}
+                Err(e) => {
+                    error!("[{}] Failed to remove from [{}]: {}", base, target.name, e);
+                    return Err(2);
+                }
+            }
+        }
+
+        if !self.keep_sources {
+            // Other pkgnames of a split package still use the sources
+            let remaining = conf.targets.iter().find_map(|target| {
+                db::list(&conf, target)
+                    .ok()?
+                    .into_iter()
+                    .find(|desc| desc.base.as_deref().unwrap_or(&desc.name) == base.as_str())
+            });
+            match remaining {
+                Some(desc) => warn!(
+                    "[{}] Sources kept, {} is still in the repo",
+                    base, desc.name
+                ),
+                None => {
+                    let _src_lock = conf.lock_src(&base).map_err(cmd_err)?;
+                    for dir in [conf.pkg_dir(&base), conf.pkg_src(&base)] {
+                        if dir.exists() {
+                            found = true;
+                            fs::remove_dir_all(&dir).map_err(cmd_err)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.from_conf {
+            if conf.remove_from_file(&base).map_err(cmd_err)? {
+                found = true;
+                println!("Removed {} from pacage.toml", base);
+            } else {
+                warn!("[{}] Not declared in pacage.toml", base);
+            }
+        }
+
+        if !found {
+            eprintln!("{} is not in the repo", self.name);
+            return Err(2);
+        }
+        Ok(())
+    }
+}
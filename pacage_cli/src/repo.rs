@@ -0,0 +1,86 @@
+use clap::{Args, Subcommand};
+use log::error;
+
+use crate::{cmd_err, CliCmd};
+use pacage::conf::{Conf, Target};
+use pacage::db::{self, RepoError};
+
+#[derive(Subcommand, Debug)]
+pub enum Repo {
+    /// Verify the db against the package files
+    Check(Check),
+    /// Regenerate the dbs from the package files
+    Rebuild(Rebuild),
+}
+
+impl CliCmd for Repo {
+    fn execute(&self, conf: Conf) -> Result<(), i32> {
+        match self {
+            Self::Check(c) => c.execute(conf),
+            Self::Rebuild(r) => r.execute(conf),
+        }
+    }
+}
+
+fn targets<'a>(conf: &'a Conf, target: &Option<String>) -> Result<Vec<&'a Target>, i32> {
+    Ok(match target {
+        Some(name) => vec![conf.target(Some(name)).map_err(cmd_err)?],
+        None => conf.targets.iter().collect(),
+    })
+}
+
+#[derive(Args, Debug)]
+pub struct Check {
+    /// Only check this target, default: every target
+    #[arg(long)]
+    pub target: Option<String>,
+}
+
+impl CliCmd for Check {
+    fn execute(&self, conf: Conf) -> Result<(), i32> {
+        let mut failed = false;
+        for target in targets(&conf, &self.target)? {
+            match db::check(&conf, target) {
+                Ok(issues) if issues.is_empty() => println!("[{}] OK", target.name),
+                Ok(issues) => {
+                    failed = true;
+                    for issue in issues {
+                        println!("[{}] {}", target.name, issue);
+                    }
+                }
+                Err(RepoError::NoRepo) => println!("[{}] No repo database", target.name),
+                Err(e) => {
+                    failed = true;
+                    error!(
+                        "[{}] Failed to read the db, see `pacage repo rebuild`: {}",
+                        target.name, e
+                    );
+                }
+            }
+        }
+        if failed {
+            return Err(2);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Rebuild {
+    /// Only rebuild this target, default: every target
+    #[arg(long)]
+    pub target: Option<String>,
+}
+
+impl CliCmd for Rebuild {
+    fn execute(&self, conf: Conf) -> Result<(), i32> {
+        for target in targets(&conf, &self.target)? {
+            let added = db::rebuild(&conf, target).map_err(|e| {
+                error!("[{}] Failed to rebuild the db: {}", target.name, e);
+                2
+            })?;
+            println!("Rebuilt [{}] with {} package(s)", target.name, added.len());
+        }
+        Ok(())
+    }
+}
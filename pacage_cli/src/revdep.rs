@@ -0,0 +1,31 @@
+use clap::Args;
+use log::info;
+
+use crate::util::{broken_packages, rebuild};
+use crate::{cmd_err, CliCmd};
+
+#[derive(Args, Debug)]
+pub struct Revdep {
+    /// Only list the broken packages
+    #[arg(long, default_value_t = false)]
+    pretend: bool,
+}
+
+impl CliCmd for Revdep {
+    fn execute(&self, mut conf: crate::Conf) -> Result<(), i32> {
+        let broken = broken_packages(&conf);
+        if broken.is_empty() {
+            println!("No broken reverse dependencies");
+            return Ok(());
+        }
+        if self.pretend {
+            for name in broken {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        let num = rebuild(&mut conf, broken).map_err(cmd_err)?;
+        info!("Rebuilt {} packages(s)", num);
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use clap::Args;
+use log::error;
+
+use crate::{cmd_err, CliCmd};
+use pacage::conf::Target;
+use pacage::db::{self, AddError};
+
+#[derive(Args, Debug)]
+pub struct Rollback {
+    /// Package name
+    pub name: String,
+
+    /// Archived version to restore ([epoch:]pkgver-pkgrel), default: the latest archived
+    pub version: Option<String>,
+
+    /// Only rollback in this target, default: every target
+    #[arg(long)]
+    pub target: Option<String>,
+
+    /// Clear the hold of the rolled back package instead, the next update replaces it
+    #[arg(long, conflicts_with = "version")]
+    pub unhold: bool,
+}
+
+impl CliCmd for Rollback {
+    fn execute(&self, conf: crate::Conf) -> Result<(), i32> {
+        let targets = match &self.target {
+            Some(name) => vec![conf.target(Some(name)).map_err(cmd_err)?],
+            None => conf.targets.iter().collect(),
+        };
+        if self.unhold {
+            return self.unhold(&conf, targets);
+        }
+        let mut found = false;
+        for target in targets {
+            match db::rollback(&conf, target, &self.name, self.version.as_deref()) {
+                Ok(version) => {
+                    found = true;
+                    println!(
+                        "Rolled back {} to {} in [{}]",
+                        self.name, version, target.name
+                    );
+                }
+                Err(AddError::NotArchived(_)) => {}
+                Err(e) => {
+                    error!(
+                        "[{}] Failed to rollback in [{}]: {}",
+                        self.name, target.name, e
+                    );
+                    return Err(2);
+                }
+            }
+        }
+        if !found {
+            eprintln!(
+                "No archived {} {}, see keep_versions",
+                self.name,
+                self.version.as_deref().unwrap_or_default()
+            );
+            return Err(2);
+        }
+        Ok(())
+    }
+}
+
+impl Rollback {
+    fn unhold(&self, conf: &crate::Conf, targets: Vec<&Target>) -> Result<(), i32> {
+        let mut found = false;
+        for target in targets {
+            match db::unhold(conf, target, &self.name) {
+                Ok(Some(version)) => {
+                    found = true;
+                    println!(
+                        "Cleared the hold of {} {} in [{}]",
+                        self.name, version, target.name
+                    );
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!(
+                        "[{}] Failed to clear the hold in [{}]: {}",
+                        self.name, target.name, e
+                    );
+                    return Err(2);
+                }
+            }
+        }
+        if !found {
+            eprintln!("{} is not held", self.name);
+            return Err(2);
+        }
+        Ok(())
+    }
+}
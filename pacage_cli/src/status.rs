@@ -4,22 +4,46 @@ use std::fs::read_dir;
 
 use crate::CliCmd;
 use clap::Args;
+use log::warn;
 use pacage::conf::Package;
 use pacage::format::{DbDesc, SrcInfo};
 
 use pacage::db;
 
 use super::cmd_err;
+use crate::util::use_flags;
 
 #[derive(Args, Debug)]
 pub struct Status {
     /// Pull repositories to check for update
     #[arg(long)]
     pub pull: bool,
+
+    /// Target repo to check, default: the first one
+    #[arg(long)]
+    pub target: Option<String>,
 }
 
 type StatusPkg = (Option<SrcInfo>, Option<DbDesc>);
 
+// An official package newer than our build would replace it on pacman -Syu
+fn warn_newer_official(sync_dbs: &[(String, Vec<DbDesc>)], db: &DbDesc, max_len: usize) {
+    for (repo, pkgs) in sync_dbs {
+        if let Some(official) = pkgs
+            .iter()
+            .find(|p| p.name == db.name && p.get_version() > db.get_version())
+        {
+            println!(
+                "{:width$} warning: newer version in [{}]: {}",
+                "",
+                repo,
+                official.get_version(),
+                width = max_len
+            );
+        }
+    }
+}
+
 impl CliCmd for Status {
     fn execute(&self, conf: crate::Conf) -> Result<(), i32> {
         if self.pull {
@@ -35,8 +59,7 @@ impl CliCmd for Status {
                     if typ.is_dir() {
                         let name = file.file_name();
                         let name = name.to_string_lossy();
-                        let pkg = SrcInfo::new(&conf.pkgs_dir(), name.as_ref(), false)
-                            .map_err(cmd_err)?;
+                        let pkg = SrcInfo::new(&conf.pkgs_dir(), name.as_ref()).map_err(cmd_err)?;
                         name_max_len = max(name_max_len, pkg.name.len());
                         version_max_len = max(version_max_len, pkg.get_version().to_string().len());
                         res.insert(pkg.name.clone(), (Some(pkg), None));
@@ -44,7 +67,8 @@ impl CliCmd for Status {
                 }
             }
         }
-        for p in db::list(&conf).map_err(cmd_err)? {
+        let target = conf.target(self.target.as_deref()).map_err(cmd_err)?;
+        for p in db::list(&conf, target).map_err(cmd_err)? {
             if let Some((_, ref mut pkg)) = res.get_mut(&p.name) {
                 name_max_len = max(name_max_len, p.name.len());
                 version_max_len = max(version_max_len, p.get_version().to_string().len());
@@ -53,6 +77,10 @@ impl CliCmd for Status {
                 res.insert(p.name.clone(), (None, Some(p)));
             }
         }
+        let sync_dbs = db::sync_list(&conf).unwrap_or_else(|e| {
+            warn!("Failed to read the sync databases: {}", e);
+            Vec::new()
+        });
         let mut confpkgs: Vec<&Package> = conf.packages.iter().collect();
         confpkgs.sort_by(|a, b| a.name.cmp(&b.name));
         for pkg in &confpkgs {
@@ -61,44 +89,65 @@ impl CliCmd for Status {
         let max_len = name_max_len + version_max_len + 2;
         for pkg in confpkgs {
             let name = &pkg.name;
+            let flags = use_flags(&conf, name);
+            let use_str = if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" USE=\"{}\"", flags.join(" "))
+            };
             if let Some(pkg) = res.remove(name) {
-                match pkg {
+                match &pkg {
                     (Some(src), Some(db)) => {
                         if src.get_version() != db.get_version() {
                             println!(
-                                "{:width$} outdated, new version: {}",
+                                "{:width$} outdated, new version: {}{}",
                                 format!("{}({})", name, db.get_version()),
                                 src.pkgver,
+                                use_str,
+                                width = max_len,
+                            );
+                        } else if db.use_flags != flags {
+                            println!(
+                                "{:width$} outdated, USE flags changed from \"{}\"{}",
+                                format!("{}({})", name, db.get_version()),
+                                db.use_flags.join(" "),
+                                use_str,
                                 width = max_len,
                             );
                         } else {
                             println!(
-                                "{:width$} Built!",
+                                "{:width$} Built!{}",
                                 format!("{}({})", name, db.get_version()),
+                                use_str,
                                 width = max_len
                             );
                         }
                     }
                     (Some(src), None) => {
                         println!(
-                            "{:width$} Downloaded, not built",
+                            "{:width$} Downloaded, not built{}",
                             format!("{}({})", name, src.get_version()),
+                            use_str,
                             width = max_len
                         );
                         // With src not installed
                     }
                     (None, Some(db)) => {
                         println!(
-                            "{:width$} Built missing src",
+                            "{:width$} Built missing src{}",
                             format!("{}({})", name, db.get_version()),
+                            use_str,
                             width = max_len
                         );
                         // Installed no src
                     }
                     _ => {}
                 }
+                if let (_, Some(db)) = &pkg {
+                    warn_newer_official(&sync_dbs, db, max_len);
+                }
             } else {
-                println!("{:1$} Not downloaded/built", name, max_len);
+                println!("{:1$} Not downloaded/built{2}", name, max_len, use_str);
             }
         }
         // TODO: real version parsing
@@ -141,6 +190,9 @@ impl CliCmd for Status {
                 }
                 _ => {}
             }
+            if let Some(db) = repo {
+                warn_newer_official(&sync_dbs, db, max_len);
+            }
         }
         Ok(())
     }
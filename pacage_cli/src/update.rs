@@ -6,7 +6,7 @@ use log::{error, info};
 use pacage::conf::Package;
 use pacage::format::SrcInfo;
 
-use crate::util::dl_and_build;
+use crate::util::{broken_packages, dl_and_build, rebuild};
 use crate::{cmd_err, CliCmd};
 use pacage::builder::Builder;
 use pacage::{
@@ -29,7 +29,8 @@ pub struct Update {
 }
 
 impl CliCmd for Update {
-    fn execute(&self, conf: Conf) -> Result<(), i32> {
+    fn execute(&self, mut conf: Conf) -> Result<(), i32> {
+        conf.force_rebuild |= self.force_rebuild;
         match &self.name {
             Some(name) => self.update_one(conf, &name),
             None => self.update_all(conf),
@@ -43,7 +44,7 @@ impl Update {
         let pkg = conf.resolve(name);
         let builder_recv = Builder::new_async(&conf);
         if self.no_fetch {
-            match SrcInfo::new(&conf.pkgs_dir(), &pkg, false) {
+            match SrcInfo::new(&conf.pkgs_dir(), &pkg) {
                 Ok(srcinfo) => {
                     conf.ensure_pkg(pkg.as_str());
                     let pkg = conf.get(pkg.as_str()).clone();
@@ -57,6 +58,16 @@ impl Update {
         }
         let num = dl_and_build(&conf, pkgbuilds, builder_recv, true).map_err(cmd_err)?;
         info!("Updated {} packages(s)", num);
+        Self::revdep_rebuild(&mut conf)
+    }
+
+    fn revdep_rebuild(conf: &mut Conf) -> Result<(), i32> {
+        let broken = broken_packages(conf);
+        if !broken.is_empty() {
+            info!("Rebuilding {} broken reverse dependencies", broken.len());
+            let num = rebuild(conf, broken).map_err(cmd_err)?;
+            info!("Rebuilt {} packages(s)", num);
+        }
         Ok(())
     }
 
@@ -71,7 +82,7 @@ impl Update {
         if self.no_fetch {
             for pkg in to_dl {
                 let pkg = conf.resolve(pkg.as_str());
-                match SrcInfo::new(&conf.pkgs_dir(), &pkg, false) {
+                match SrcInfo::new(&conf.pkgs_dir(), &pkg) {
                     Ok(srcinfo) => {
                         conf.ensure_pkg(pkg.as_str());
                         let pkg = conf.get(pkg.as_str()).clone();
@@ -86,6 +97,6 @@ impl Update {
         };
         let num = dl_and_build(&conf, pkgbuilds, builder_recv, true).map_err(cmd_err)?;
         info!("Updated {} packages(s)", num);
-        Ok(())
+        Self::revdep_rebuild(&mut conf)
     }
 }
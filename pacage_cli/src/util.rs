@@ -1,20 +1,129 @@
 use crossbeam_channel::{unbounded, Receiver};
-use log::{error, info};
+use log::{error, info, warn};
 use pacage::{
     builder::{Builder, BuilderError},
-    conf::{Conf, Package},
-    db,
+    conf::{Conf, Package, Target},
+    db::{self, RepoError},
+    fingerprint,
     format::{DbDesc, SrcInfo},
     patch::patch,
+    pkgrel, revdep,
+    use_flags::UseFlags,
 };
+use std::collections::{BTreeSet, HashMap};
 
-fn is_outdated(dbpkgs: &Vec<DbDesc>, pkg: &SrcInfo) -> bool {
-    for dbpkg in dbpkgs {
-        if dbpkg.name == pkg.name {
-            return dbpkg.get_version() != pkg.get_version();
+// Effective USE flags of a package, empty if the mapping is invalid
+pub fn use_flags(conf: &Conf, name: &str) -> Vec<String> {
+    match UseFlags::new(conf, name) {
+        Ok(flags) => flags.list(),
+        Err(e) => {
+            error!("[{}] Failed to get USE flags: {}", name, e);
+            Vec::new()
         }
     }
-    false
+}
+
+// Build inputs fingerprint, None (so rebuilt) if it cannot be computed
+pub fn build_fingerprint(
+    conf: &Conf,
+    pkg: &Package,
+    target: &Target,
+    image: Option<&str>,
+) -> Option<String> {
+    fingerprint::compute(conf, pkg, target, image)
+        .inspect_err(|e| {
+            error!(
+                "[{}] Failed to compute the build fingerprint: {}",
+                pkg.name, e
+            )
+        })
+        .ok()
+}
+
+// The package built with the same inputs, fetched into the target repo dir
+fn from_binary_cache(conf: &Conf, srcinfo: &SrcInfo, target: &Target) -> bool {
+    let (Some(cache), Some(fingerprint)) = (&conf.binary_cache, &srcinfo.fingerprint) else {
+        return false;
+    };
+    match cache.fetch(fingerprint, &conf.repo_dir(target)) {
+        // Every package of a split package
+        Ok(Some(files)) => db::has_pkgfiles(&files, srcinfo),
+        Ok(None) => false,
+        Err(e) => {
+            error!("[{}] Binary cache lookup failed: {}", srcinfo.name, e);
+            false
+        }
+    }
+}
+
+// After db::add, the packages are signed
+pub fn upload_to_binary_cache(conf: &Conf, srcinfo: &SrcInfo, target: &Target) {
+    let (Some(cache), Some(fingerprint)) = (&conf.binary_cache, &srcinfo.fingerprint) else {
+        return;
+    };
+    let repo_dir = conf.repo_dir(target);
+    let Some(mut files) = db::find_pkgfiles(&repo_dir, srcinfo) else {
+        return;
+    };
+    if conf.gpg_key.is_some() {
+        let sigs: Vec<String> = files.iter().map(|file| format!("{}.sig", file)).collect();
+        files.extend(sigs.into_iter().filter(|sig| repo_dir.join(sig).exists()));
+    }
+    if let Err(e) = cache.upload(fingerprint, &repo_dir, &files) {
+        error!(
+            "[{}] Failed to upload to the binary cache: {}",
+            srcinfo.name, e
+        );
+    }
+}
+
+// A same-version rebuild gets a new local pkgrel, see pkgrel::bump
+pub fn bump_pkgrel(conf: &Conf, dbpkgs: &[DbDesc], srcinfo: &mut SrcInfo) {
+    let res = match db::same_release(dbpkgs, srcinfo) {
+        Some(dbpkg) => pkgrel::bump(conf, srcinfo, dbpkg.get_version()).map(|bumped| {
+            if bumped {
+                info!(
+                    "[{}] Rebuilding {} as {}",
+                    srcinfo.name,
+                    dbpkg.get_version(),
+                    srcinfo.get_version()
+                )
+            }
+        }),
+        // Undo the bump of a previous target
+        None => pkgrel::apply(conf, srcinfo),
+    };
+    if let Err(e) = res {
+        error!("[{}] Failed to set the pkgrel: {}", srcinfo.name, e);
+    }
+}
+
+// Targets in which the package is missing or outdated, with their packages
+fn outdated_targets<'a>(
+    conf: &'a Conf,
+    dbs: &'a [Result<Vec<DbDesc>, RepoError>],
+    srcinfo: &SrcInfo,
+    pkg: &Package,
+    image: Option<&str>,
+) -> Vec<(&'a Target, &'a [DbDesc])> {
+    let use_flags = use_flags(conf, &srcinfo.name);
+    conf.targets
+        .iter()
+        .zip(dbs)
+        .filter(|(target, dbpkgs)| match dbpkgs {
+            // Held by a rollback, even with -f
+            Ok(dbpkgs) if conf.force_rebuild => !dbpkgs
+                .iter()
+                .any(|dbpkg| dbpkg.name == srcinfo.name && dbpkg.hold),
+            _ if conf.force_rebuild => true,
+            Ok(dbpkgs) => {
+                let fingerprint = build_fingerprint(conf, pkg, target, image);
+                db::is_outdated(dbpkgs, srcinfo, &use_flags, fingerprint.as_deref())
+            }
+            Err(_) => true,
+        })
+        .map(|(target, dbpkgs)| (target, dbpkgs.as_deref().unwrap_or_default()))
+        .collect()
 }
 
 pub fn dl_and_build(
@@ -25,19 +134,17 @@ pub fn dl_and_build(
 ) -> Result<usize, String> {
     let (src_to_dl_sender, src_to_dl) = unbounded::<(SrcInfo, Package)>();
     let (source_dl_sender, source_dl) = unbounded::<(SrcInfo, Package)>();
-    let dbpkgs = db::list(&conf);
+    let dbs: Vec<_> = conf.targets.iter().map(|t| db::list(conf, t)).collect();
+    let image = fingerprint::image_digest(conf);
     // Check if package is already there
     // TODO: spawn it own thread
     // TODO: check if pkg is lower
     while let Ok((wanted_srcinfo, wanted_pkg)) = pkgbuilds.recv() {
-        if let Ok(dbpkgs) = &dbpkgs {
-            if is_outdated(dbpkgs, &wanted_srcinfo) {
-                src_to_dl_sender.send((wanted_srcinfo, wanted_pkg));
-            } else {
-                info!("[{}] Already up to date", wanted_srcinfo.name);
-            }
-        } else {
+        if !outdated_targets(conf, &dbs, &wanted_srcinfo, &wanted_pkg, image.as_deref()).is_empty()
+        {
             src_to_dl_sender.send((wanted_srcinfo, wanted_pkg));
+        } else {
+            info!("[{}] Already up to date", wanted_srcinfo.name);
         }
     }
     drop(src_to_dl_sender);
@@ -47,41 +154,113 @@ pub fn dl_and_build(
         Err(e) => Err(format!("Failed to recv builder: {}", e))?,
         Ok(Err(e)) => Err(format!("Failed to create builder: {}", e))?,
     };
+    // Pulled by the builder if it was missing
+    let image = image.or_else(|| fingerprint::image_digest(conf));
 
     // TOOD: spawn thread
     builder
         .download_srcs(&conf, src_to_dl, source_dl_sender)
         .unwrap();
 
-    let mut pkgbuilds = Vec::new();
+    let mut built: HashMap<&str, Vec<SrcInfo>> = HashMap::new();
+    // Built ones, not the ones found in the binary cache
+    let mut uploads = Vec::new();
     while let Ok((srcinfo, pkg)) = source_dl.recv() {
-        if let Ok(dbpkgs) = &dbpkgs {
-            if !is_outdated(dbpkgs, &srcinfo) {
-                info!("[{}] Already up to date", srcinfo.name);
-                continue;
-            }
+        let targets = outdated_targets(conf, &dbs, &srcinfo, &pkg, image.as_deref());
+        if targets.is_empty() {
+            info!("[{}] Already up to date", srcinfo.name);
+            continue;
         }
         if let Err(e) = patch(&conf, &srcinfo) {
             let e = format!("[{}] Skipping build, failed to patch: {}", srcinfo.name, e);
             if continue_on_e {
                 error!("{}", e);
+                continue;
             } else {
                 return Err(e);
             }
-        } else if let Err(e) = builder.build_pkg(&conf, &pkg) {
-            let e = format!("[{}] Skipping build, failed to build: {}", srcinfo.name, e);
-            if continue_on_e {
-                error!("{}", e);
+        }
+        for (target, dbpkgs) in targets {
+            let mut srcinfo = srcinfo.clone();
+            bump_pkgrel(conf, dbpkgs, &mut srcinfo);
+            srcinfo.fingerprint = build_fingerprint(conf, &pkg, target, image.as_deref());
+            if from_binary_cache(conf, &srcinfo, target) {
+                info!("[{}] Found in the binary cache", srcinfo.name);
+                built.entry(target.name.as_str()).or_default().push(srcinfo);
+                continue;
+            }
+            if let Err(e) = builder.build_pkg(&conf, &pkg, target) {
+                let e = format!(
+                    "[{}] Skipping {} build, failed to build: {}",
+                    srcinfo.name, target.name, e
+                );
+                if continue_on_e {
+                    error!("{}", e);
+                } else {
+                    return Err(e);
+                }
             } else {
-                return Err(e);
+                uploads.push((target, srcinfo.clone()));
+                built.entry(target.name.as_str()).or_default().push(srcinfo);
             }
-        } else {
-            pkgbuilds.push(srcinfo);
         }
     }
 
-    if !pkgbuilds.is_empty() {
-        db::add(&conf, &pkgbuilds).map_err(|e| e.to_string())?;
+    for target in &conf.targets {
+        if let Some(pkgbuilds) = built.get(target.name.as_str()) {
+            db::add(&conf, target, pkgbuilds).map_err(|e| e.to_string())?;
+        }
+    }
+    for (target, srcinfo) in uploads {
+        upload_to_binary_cache(conf, &srcinfo, target);
+    }
+    Ok(built.values().map(|pkgbuilds| pkgbuilds.len()).sum())
+}
+
+// Package bases linked against a soname nothing provides anymore, in any target
+pub fn broken_packages(conf: &Conf) -> BTreeSet<String> {
+    let mut res = BTreeSet::new();
+    for target in &conf.targets {
+        match revdep::broken(conf, target) {
+            Ok(broken) => {
+                for pkg in broken {
+                    warn!(
+                        "[{}] {} is linked against missing {}",
+                        pkg.base,
+                        pkg.name,
+                        pkg.missing.into_iter().collect::<Vec<_>>().join(" ")
+                    );
+                    res.insert(pkg.base);
+                }
+            }
+            Err(e) => error!(
+                "Failed to check the {} reverse dependencies: {}",
+                target.name, e
+            ),
+        }
+    }
+    res
+}
+
+// Rebuild already downloaded packages, even without changes: the same version gets a new
+// local pkgrel (see bump_pkgrel)
+pub fn rebuild(conf: &mut Conf, names: BTreeSet<String>) -> Result<usize, String> {
+    let (pkgbuildssender, pkgbuilds) = unbounded::<(SrcInfo, Package)>();
+    let builder_recv = Builder::new_async(conf);
+    for name in names {
+        match SrcInfo::new(&conf.pkgs_dir(), &name) {
+            Ok(srcinfo) => {
+                conf.ensure_pkg(&name);
+                let pkg = conf.get(&name).clone();
+                pkgbuildssender.send((srcinfo, pkg)).unwrap();
+            }
+            Err(e) => error!("[{}] Fail to read .SRCINFO: {}", name, e),
+        }
     }
-    Ok(pkgbuilds.len())
+    drop(pkgbuildssender);
+    let force_rebuild = conf.force_rebuild;
+    conf.force_rebuild = true;
+    let res = dl_and_build(conf, pkgbuilds, builder_recv, true);
+    conf.force_rebuild = force_rebuild;
+    res
 }
@@ -20,7 +20,11 @@ function cleanup() {
   # echo "Cleaning up build directory"
   rm -rf /build/srcs/*/pkg
   # chown -R root:root . $CCACHE_DIR /build/srcs
-  chown -R root:root /build/srcs/$pkg /build/pkgs/$pkg
+  # srcs/$pkg is missing for a srcinfo before any download
+  for dir in /build/srcs/$pkg /build/pkgs/$pkg ; do
+    [ -d $dir ] && chown -R root:root $dir
+  done
+  return 0
 }
 trap cleanup EXIT ERR
 
@@ -46,6 +50,8 @@ init_system() {
 
     # pacman-key --refresh-keys
   fi
+  # Used as base when makepkg_base = "image"
+  cp /etc/makepkg.conf /build/cache/makepkg.conf
 }
 
 pacage_build() (
@@ -64,14 +70,32 @@ pacage_build() (
   source PKGBUILD
 
   # Check if variable is defined
-  pacman_install ${depends[@]} ${makedepends[@]} ${checkdepends[@]}
+  if [[ " $PACAGE_MAKEPKG_FLAGS " == *" --nocheck "* ]]; then
+    pacman_install ${depends[@]} ${makedepends[@]} $PACAGE_TOOLCHAIN_PKGS
+  else
+    pacman_install ${depends[@]} ${makedepends[@]} ${checkdepends[@]} $PACAGE_TOOLCHAIN_PKGS
+  fi
 
   git --version
 
+  # Build from a copy of the prepared sources, one per target
+  if [ -n "$PACAGE_BUILDDIR" ] ; then
+    rm -rf $PACAGE_BUILDDIR/$pkg
+    mkdir -p $PACAGE_BUILDDIR
+    cp -a /build/srcs/$pkg $PACAGE_BUILDDIR/$pkg
+    chown -R ${usr}:${usr} $PACAGE_BUILDDIR/$pkg
+    export BUILDDIR=$PACAGE_BUILDDIR
+  fi
+
+  local repo_dir=${PACAGE_REPO_DIR:-/build/repo}
+  mkdir -p $repo_dir
   local pkgdest=$(mktemp -d)
   chown -R ${usr}:${usr} . $pkgdest $makepkg_conf $CCACHE_DIR /build/srcs/$pkg
-  PKGDEST=$pkgdest runuser -u $usr -m -- makepkg -f --skippgpcheck --skipinteg --config $makepkg_conf --noextract
-  mv $pkgdest/* /build/repo
+  PKGDEST=$pkgdest runuser -u $usr -m -- makepkg -f --skippgpcheck --skipinteg --config $makepkg_conf --noextract $PACAGE_MAKEPKG_FLAGS
+  mv $pkgdest/* $repo_dir
+  if [ -n "$PACAGE_BUILDDIR" ] ; then
+    rm -rf $PACAGE_BUILDDIR/$pkg
+  fi
   runuser -u $usr -- makepkg --printsrcinfo > .SRCINFO
   ccache -s
 )
@@ -110,6 +134,41 @@ pacage_get() (
   # maybe it should print some info in the src dir
 )
 
+# Print the .SRCINFO as the package user, the PKGBUILD is only sourced inside the container
+pacage_srcinfo() (
+  local pkg=$1
+  local usr=$pkg"_builder"
+  if ! id $usr ; then
+    useradd -U -M $usr
+  fi
+  chown -R ${usr}:${usr} .
+  runuser -u $usr -- bash -c "makepkg --printsrcinfo > .SRCINFO"
+)
+
+# Run in a throw-away container: install the PGO instrumented package and train it
+pacage_train() (
+  local pkg=$1
+  yes | pacman -Syu --cachedir /build/cache/pacman --noconfirm
+  yes | pacman -U --cachedir /build/cache/pacman --noconfirm $PACAGE_PGO_PKGS/*.pkg.tar*
+  mkdir -p $PACAGE_PGO_DIR
+  bash -c "$PACAGE_PGO_TRAIN"
+  # clang and rustc profiles need to be merged
+  if compgen -G "$PACAGE_PGO_DIR/*.profraw" > /dev/null ; then
+    pacman_install llvm
+    llvm-profdata merge -output=$PACAGE_PGO_DIR/default.profdata $PACAGE_PGO_DIR/*.profraw
+    rm $PACAGE_PGO_DIR/*.profraw
+  fi
+  chmod -R a+rwX $PACAGE_PGO_DIR
+)
+
+# Interactive kernel configuration, the terminal is attached
+pacage_kconfig() (
+  yes | pacman -Syu --cachedir /build/cache/pacman --noconfirm
+  pacman_install ncurses bc flex bison
+  cd $PACAGE_KCONFIG_DIR
+  make menuconfig
+)
+
 # we remove [0] which is the action
 echo action $action
 echo pkg $pkg
@@ -128,6 +187,17 @@ case $action in
     pacage_build $pkg
     popd
   ;;
+  "srcinfo")
+    pushd pkgs/$pkg
+    pacage_srcinfo $pkg
+    popd
+  ;;
+  "train")
+    pacage_train $pkg
+  ;;
+  "kconfig")
+    pacage_kconfig $pkg
+  ;;
   *)
     "Invalid action: $action"
     exit 2